@@ -0,0 +1,93 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Destination of an outbound connection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Address {
+    /// Creates an address from a host, which is either a domain name or an IP address optionally
+    /// enclosed in brackets, and a port.
+    pub fn new(host: &str, port: u16) -> Self {
+        let ip = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+
+        match ip.parse::<IpAddr>() {
+            Ok(ip) => Self::Ip(SocketAddr::new(ip, port)),
+            Err(_) => Self::Domain(host.to_string(), port),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Self::Ip(addr) => addr.port(),
+            Self::Domain(_, port) => *port,
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    /// Parses `<host>:<port>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("port is missing in `{s}`"))?;
+        let port = port.parse().map_err(|_| format!("invalid port in `{s}`"))?;
+        Ok(Self::new(host, port))
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Ip(addr)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(addr) => addr.fmt(f),
+            Self::Domain(domain, port) => write!(f, "{domain}:{port}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        assert_eq!(
+            Address::new("192.0.2.1", 80),
+            Address::Ip("192.0.2.1:80".parse().unwrap())
+        );
+        assert_eq!(
+            Address::new("[2001:db8::1]", 443),
+            Address::Ip("[2001:db8::1]:443".parse().unwrap())
+        );
+        assert_eq!(
+            Address::new("example.com", 80),
+            Address::Domain("example.com".to_string(), 80)
+        );
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("[::1]:80".parse(), Ok(Address::new("::1", 80)));
+        assert_eq!(
+            "example.com:80".parse(),
+            Ok(Address::new("example.com", 80))
+        );
+        assert!("example.com".parse::<Address>().is_err());
+        assert!("example.com:http".parse::<Address>().is_err());
+    }
+}
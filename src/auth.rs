@@ -0,0 +1,51 @@
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use std::collections::HashMap;
+use subtle::{Choice, ConstantTimeEq};
+
+/// Credentials clients authenticate with.
+///
+/// ```toml
+/// [users]
+/// alice = "secret"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Auth {
+    /// Passwords of the users, keyed by name.
+    users: HashMap<String, String>,
+}
+
+impl Auth {
+    /// Adds a user.
+    pub fn user(mut self, name: impl Into<String>, password: impl Into<String>) -> Self {
+        self.users.insert(name.into(), password.into());
+        self
+    }
+
+    /// Verifies the password of the user in constant time, comparing digests of a fixed length.
+    /// Unknown users are compared likewise, not to tell which users exist.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let expected = self.users.get(user);
+        let expected_digest = digest(&SHA256, expected.map_or("", String::as_str).as_bytes());
+        let digest = digest(&SHA256, password.as_bytes());
+        let matches = expected_digest.as_ref().ct_eq(digest.as_ref());
+        (matches & Choice::from(u8::from(expected.is_some()))).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let auth: Auth = toml::from_str("[users]\nalice = \"secret\"").unwrap();
+        assert_eq!(auth, Auth::default().user("alice", "secret"));
+        assert!(auth.verify("alice", "secret"));
+        assert!(!auth.verify("alice", "Secret"));
+        assert!(!auth.verify("alice", ""));
+        assert!(!auth.verify("bob", "secret"));
+        assert!(!auth.verify("bob", ""));
+    }
+}
@@ -0,0 +1,297 @@
+//! Client side of the proxy protocols, negotiating connections through a proxy server.
+
+use crate::socks::{self, v4, v5, Decode};
+use crate::{Address, Upstream};
+use base64::prelude::*;
+use std::fmt;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Reply of a proxy server to a connection request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    /// Status code of the HTTP response.
+    Http(u16),
+    Socks4(v4::Status),
+    Socks5(v5::Reply),
+}
+
+impl Reply {
+    /// Returns whether the connection is established.
+    pub fn is_success(&self) -> bool {
+        match self {
+            Self::Http(status) => (200..300).contains(status),
+            Self::Socks4(status) => *status == v4::Status::Granted,
+            Self::Socks5(reply) => *reply == v5::Reply::Succeeded,
+        }
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(status) => write!(f, "HTTP status {status}"),
+            Self::Socks4(status) => write!(f, "SOCKS4 reply {} ({status:?})", status.code()),
+            Self::Socks5(reply) => write!(f, "SOCKS5 reply {} ({reply:?})", reply.code()),
+        }
+    }
+}
+
+/// Username and password to authenticate to a proxy server with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Requests `proxy` to connect to `addr` over `stream`, which is connected to the proxy,
+/// authenticating with `credentials` if any.
+///
+/// Returns the reply of the proxy, whether or not the connection is established. Data following
+/// the reply is left unread in `stream`.
+pub async fn connect<S>(
+    stream: &mut S,
+    proxy: &Upstream,
+    addr: &Address,
+    credentials: Option<&Credentials>,
+) -> io::Result<Reply>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match proxy {
+        Upstream::Http(_) => http_connect(stream, addr, credentials).await,
+        Upstream::Socks4(_) => {
+            // SOCKS4 carries no password, only the user ID
+            let user = credentials.map_or("", |c| c.username.as_str());
+            socks4_connect(stream, addr, user).await
+        }
+        Upstream::Socks5(_) => socks5_connect(stream, addr, credentials).await,
+    }
+}
+
+/// Connects with the `CONNECT` method of HTTP, authenticating with the Basic scheme if
+/// `credentials` are given.
+pub async fn http_connect<S>(
+    stream: &mut S,
+    addr: &Address,
+    credentials: Option<&Credentials>,
+) -> io::Result<Reply>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut req = format!("CONNECT {addr} HTTP/1.1\r\nHost: {addr}\r\n");
+    if let Some(c) = credentials {
+        let token = BASE64_STANDARD.encode(format!("{}:{}", c.username, c.password));
+        req += &format!("Proxy-Authorization: Basic {token}\r\n");
+    }
+    req += "\r\n";
+    stream.write_all(req.as_bytes()).await?;
+
+    // reads byte by byte not to consume any data following the header
+    let mut header = Vec::with_capacity(256);
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= 8192 {
+            return Err(io::Error::other("response header is too long"));
+        }
+        header.push(stream.read_u8().await?);
+    }
+
+    header
+        .split(|&b| b == b' ')
+        .nth(1)
+        .and_then(|s| std::str::from_utf8(s).ok())
+        .and_then(|s| s.parse().ok())
+        .map(Reply::Http)
+        .ok_or_else(|| io::Error::other("malformed response"))
+}
+
+/// Connects with SOCKS4, or SOCKS4a if `addr` is a domain name, on behalf of `user`.
+pub async fn socks4_connect<S>(stream: &mut S, addr: &Address, user: &str) -> io::Result<Reply>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let req = v4::Request::Connect(addr.clone().into(), user.to_string());
+    socks::write(stream, &req).await?;
+
+    let res: v4::Response = read(stream).await?;
+    Ok(Reply::Socks4(res.status))
+}
+
+/// Connects with SOCKS5, offering the username/password method if `credentials` are given besides
+/// no authentication.
+pub async fn socks5_connect<S>(
+    stream: &mut S,
+    addr: &Address,
+    credentials: Option<&Credentials>,
+) -> io::Result<Reply>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut methods = vec![v5::Method::NO_AUTH];
+    if credentials.is_some() {
+        methods.push(v5::Method::USERNAME_PASSWORD);
+    }
+    socks::write(stream, &v5::Greeting { methods }).await?;
+
+    let selection: v5::MethodSelection = read(stream).await?;
+    match (selection.method, credentials) {
+        (v5::Method::NO_AUTH, _) => {}
+        (v5::Method::USERNAME_PASSWORD, Some(c)) => {
+            let req = v5::AuthRequest {
+                username: c.username.clone(),
+                password: c.password.clone(),
+            };
+            socks::write(stream, &req).await?;
+
+            let res: v5::AuthResponse = read(stream).await?;
+            if !res.success {
+                return Err(io::Error::other("authentication failed"));
+            }
+        }
+        _ => return Err(io::Error::other("no acceptable authentication methods")),
+    }
+
+    socks::write(stream, &v5::Request::Connect(addr.clone().into())).await?;
+
+    let res: v5::Response = read(stream).await?;
+    Ok(Reply::Socks5(res.reply))
+}
+
+/// Reads a message byte by byte not to consume any data following it.
+async fn read<T, S>(stream: &mut S) -> io::Result<T>
+where
+    T: Decode,
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(32);
+    loop {
+        match T::decode(&mut &buf[..]) {
+            Ok(msg) => break Ok(msg),
+            Err(socks::Error::NeedMoreData) => {}
+            Err(e) => break Err(e.into()),
+        }
+        buf.push(stream.read_u8().await?);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_socks4_connect() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut buf = [0; 19];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..9], &[4, 1, 0, 80, 0, 0, 0, 1, 0]);
+            assert_eq!(&buf[9..], b"hoge.test\0");
+            server
+                .write_all(&[0, 90, 0, 0, 0, 0, 0, 0, 0x68])
+                .await
+                .unwrap();
+        });
+
+        let reply = socks4_connect(&mut client, &Address::new("hoge.test", 80), "")
+            .await
+            .unwrap();
+        assert_eq!(reply, Reply::Socks4(v4::Status::Granted));
+        assert!(reply.is_success());
+        assert_eq!(client.read_u8().await.unwrap(), 0x68);
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let _ = server.read(&mut buf).await.unwrap();
+            server
+                .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let reply = http_connect(&mut client, &Address::new("example.com", 443), None)
+            .await
+            .unwrap();
+        assert_eq!(reply, Reply::Http(403));
+        assert!(!reply.is_success());
+        assert_eq!(reply.to_string(), "HTTP status 403");
+    }
+
+    fn alice() -> Credentials {
+        Credentials {
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_auth() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let n = server.read(&mut buf).await.unwrap();
+            server
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        });
+
+        let addr = Address::new("example.com", 443);
+        let reply = http_connect(&mut client, &addr, Some(&alice()))
+            .await
+            .unwrap();
+        assert!(reply.is_success());
+        let req = server.await.unwrap();
+        assert!(req.contains("Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect_auth() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut buf = [0; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 2, 0, 2]);
+            server.write_all(&[5, 2]).await.unwrap();
+
+            let mut buf = [0; 14];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..2], &[1, 5]);
+            assert_eq!(&buf[2..7], b"alice");
+            assert_eq!(buf[7], 6);
+            assert_eq!(&buf[8..14], b"secret");
+            server.write_all(&[1, 0]).await.unwrap();
+
+            let mut buf = [0; 16];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..5], &[5, 1, 0, 3, 9]);
+            server
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        let addr = Address::new("hoge.test", 80);
+        let reply = socks5_connect(&mut client, &addr, Some(&alice()))
+            .await
+            .unwrap();
+        assert_eq!(reply, Reply::Socks5(v5::Reply::Succeeded));
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect_auth_failure() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut buf = [0; 3];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(&[5, 2]).await.unwrap();
+        });
+
+        // the username/password method is not offered without credentials
+        let addr = Address::new("hoge.test", 80);
+        assert!(socks5_connect(&mut client, &addr, None).await.is_err());
+    }
+}
@@ -0,0 +1,528 @@
+use crate::limit::Limited;
+use crate::resolver::{DnsResolver, NameServer};
+use crate::route::Config as RouteConfig;
+use crate::server::{Registry, Settings};
+#[cfg(target_os = "linux")]
+use crate::Identify;
+use crate::{
+    Auth, ClientLimit, Dialer, Filter, Hosts, ProxyProtocol, Retry, Router, Service, SessionLimit,
+    Upstream,
+};
+use anyhow::{anyhow, Context as _, Result};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Configuration of listeners, usually read from a TOML file:
+///
+/// ```toml
+/// [upstreams]
+/// corp = "http://proxy.corp.example:3128"
+///
+/// [[listener]]
+/// listen = ["127.0.0.1:8080"]
+/// provider = "http"
+/// rules = "/etc/juno/rules.toml"
+/// proxy_protocol = ["10.0.0.0/8"]
+/// forwarded = true
+/// identify_clients = true
+///
+/// [listener.auth.users]
+/// alice = "secret"
+///
+/// [listener.limits]
+/// max_sessions = 1000
+/// max_sessions_per_client = 16
+///
+/// [listener.socket]
+/// reuse_port = 4
+///
+/// [[listener]]
+/// listen = ["127.0.0.1:1080", "unix:/run/juno/socks.sock"]
+/// provider = "socks"
+///
+/// [listener.socket]
+/// mode = 0o660
+/// group = "proxy"
+///
+/// [listener.dialer]
+/// bind_to = "192.0.2.10"
+/// connect_timeout = "5s"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Upstream proxies available to the routing rules of all listeners.
+    #[serde(default)]
+    pub upstreams: HashMap<String, Upstream>,
+
+    #[serde(default, rename = "listener")]
+    pub listeners: Vec<ListenerConfig>,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let config: Self =
+            toml::from_str(&s).with_context(|| format!("failed to parse {}", path.display()))?;
+
+        if config.listeners.is_empty() {
+            return Err(anyhow!("no listener is defined in {}", path.display()));
+        }
+        if let Some(i) = config.listeners.iter().position(|l| l.listen.is_empty()) {
+            return Err(anyhow!(
+                "listener #{} has no address to listen on in {}",
+                i + 1,
+                path.display()
+            ));
+        }
+
+        let mut addrs = HashSet::new();
+        for addr in config.listeners.iter().flat_map(|l| &l.listen) {
+            if !addrs.insert(addr) {
+                return Err(anyhow!(
+                    "{addr} is listened on more than once in {}",
+                    path.display()
+                ));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Addresses to listen on, each either `host:port`, `unix:<path>` or `systemd:<name>` for the
+    /// sockets activated by systemd with the `FileDescriptorName=` of `name`.
+    #[serde(default)]
+    pub listen: Vec<String>,
+
+    /// Name of the provider serving connections.
+    pub provider: String,
+
+    /// Credentials clients must authenticate with, if any.
+    pub auth: Option<Auth>,
+
+    /// File of outbound routing rules.
+    pub rules: Option<PathBuf>,
+
+    #[serde(default)]
+    pub dialer: DialerConfig,
+
+    #[serde(default)]
+    pub limits: Limits,
+
+    #[serde(default)]
+    pub socket: SocketConfig,
+
+    /// Addresses of proxies in front of the listener, whose connections start with a PROXY
+    /// protocol header telling the addresses of clients.
+    #[serde(default)]
+    pub proxy_protocol: Vec<IpNet>,
+
+    /// Whether to tell servers the addresses of clients, in `Forwarded` headers of HTTP.
+    #[serde(default)]
+    pub forwarded: bool,
+
+    /// Whether to identify the local processes of clients connected over TCP loopback, on Linux,
+    /// for logs. They are identified anyway if the rules match users or groups.
+    #[serde(default)]
+    pub identify_clients: bool,
+}
+
+impl ListenerConfig {
+    /// Creates the service of the listener, routing to `upstreams` in addition to the ones
+    /// defined in the rules file, and counting sessions in `limits`.
+    pub async fn service(
+        &self,
+        registry: &Registry,
+        upstreams: &HashMap<String, Upstream>,
+        limits: &ActiveLimits,
+    ) -> Result<Service> {
+        let mut config = match &self.rules {
+            Some(path) => RouteConfig::load(path)?,
+            None => RouteConfig::default(),
+        };
+        for (name, upstream) in upstreams {
+            config
+                .upstreams
+                .entry(name.clone())
+                .or_insert_with(|| upstream.clone());
+        }
+
+        let router = Router::new(config, self.dialer.build().await?)?;
+        #[cfg(target_os = "linux")]
+        let identify = self.identify_clients || router.matches_peer_cred();
+        let settings = Settings::new(Arc::new(router))
+            .auth(self.auth.clone().map(Arc::new))
+            .client_limit(limits.clients.clone())
+            .forwarded(self.forwarded);
+        let mut service = registry.create(&self.provider, &settings)?;
+        if !self.proxy_protocol.is_empty() {
+            service = Service::new(ProxyProtocol::new(service, self.proxy_protocol.clone()));
+        }
+        #[cfg(target_os = "linux")]
+        if identify {
+            service = Service::new(Identify::new(service, self.proxy_protocol.clone()));
+        }
+
+        match &limits.sessions {
+            Some(limit) => Ok(Service::new(Limited::new(service, Arc::clone(limit)))),
+            None => Ok(service),
+        }
+    }
+}
+
+/// Settings of outbound connections.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DialerConfig {
+    /// Source address of outbound connections.
+    pub bind_to: Option<String>,
+
+    /// Timeout of each connection attempt.
+    #[serde(deserialize_with = "optional_duration")]
+    pub connect_timeout: Option<Duration>,
+
+    /// Timeout of establishing a connection, including retries.
+    #[serde(deserialize_with = "optional_duration")]
+    pub dial_timeout: Option<Duration>,
+
+    pub retries: u32,
+
+    /// Delay before the first retry, doubled on each subsequent retry.
+    #[serde(deserialize_with = "duration")]
+    pub retry_backoff: Duration,
+
+    /// File of static overrides of destinations.
+    pub hosts: Option<PathBuf>,
+
+    /// Allows connections to private, loopback and other special-purpose addresses, which are
+    /// denied by default.
+    pub allow_private_destinations: bool,
+
+    pub deny_destinations: Vec<IpNet>,
+
+    /// Ranges of addresses connections are allowed to regardless of denials, requiring any
+    /// unless `allow_private_destinations` is set.
+    pub allow_destinations: Vec<IpNet>,
+
+    /// DNS servers to resolve names with, instead of the system resolver.
+    pub dns: Vec<NameServer>,
+
+    /// Maximum number of DNS records to cache.
+    pub dns_cache_size: usize,
+}
+
+impl Default for DialerConfig {
+    fn default() -> Self {
+        Self {
+            bind_to: None,
+            connect_timeout: None,
+            dial_timeout: None,
+            retries: 0,
+            retry_backoff: Retry::default().backoff,
+            hosts: None,
+            allow_private_destinations: false,
+            deny_destinations: vec![],
+            allow_destinations: vec![],
+            dns: vec![],
+            dns_cache_size: 1024,
+        }
+    }
+}
+
+impl DialerConfig {
+    pub async fn build(&self) -> Result<Dialer> {
+        let mut dialer = if let Some(a) = &self.bind_to {
+            Dialer::bind(a).await?
+        } else {
+            Dialer::default()
+        };
+        if let Some(path) = &self.hosts {
+            dialer = dialer.hosts(Arc::new(Hosts::load(path)?));
+        }
+        let denies = !self.allow_private_destinations || !self.deny_destinations.is_empty();
+        if !denies && !self.allow_destinations.is_empty() {
+            return Err(anyhow!(
+                "allowed destinations have no effect without denied destinations"
+            ));
+        }
+        if denies {
+            let mut filter = if self.allow_private_destinations {
+                Filter::empty()
+            } else {
+                Filter::default()
+            };
+            for net in &self.deny_destinations {
+                filter = filter.deny(*net);
+            }
+            for net in &self.allow_destinations {
+                filter = filter.allow(*net);
+            }
+            dialer = dialer.filter(Some(Arc::new(filter)));
+        }
+        if !self.dns.is_empty() {
+            let resolver = DnsResolver::new(self.dns.iter().cloned(), self.dns_cache_size);
+            dialer = dialer.resolver(Arc::new(resolver));
+        }
+
+        Ok(dialer
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.dial_timeout)
+            .retry(Retry {
+                attempts: self.retries,
+                backoff: self.retry_backoff,
+                ..Default::default()
+            }))
+    }
+}
+
+/// Settings of sockets listened on.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketConfig {
+    /// Permissions of the socket file, like `0o660`.
+    pub mode: Option<u32>,
+
+    /// Name or ID of the user owning the socket file.
+    pub owner: Option<String>,
+
+    /// Name or ID of the group owning the socket file.
+    pub group: Option<String>,
+
+    /// Number of TCP sockets bound to each address with `SO_REUSEPORT`, each accepting
+    /// connections on its own task. Changing it takes a restart, as sockets are kept on reload.
+    pub reuse_port: Option<NonZeroUsize>,
+}
+
+/// Limits of a listener.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Maximum number of concurrent sessions. Connections over the limit wait to be accepted.
+    pub max_sessions: Option<NonZeroUsize>,
+
+    /// Maximum number of concurrent sessions of each client IP address. Connections over the
+    /// limit are rejected.
+    pub max_sessions_per_client: Option<NonZeroUsize>,
+}
+
+impl Limits {
+    /// Creates the limits to count sessions in, reusing the ones of `current` of an earlier load
+    /// if any, so that sessions started before keep counting. Reused limits are only resized to
+    /// these by [`ActiveLimits::resize`].
+    pub fn activate(&self, current: Option<&ActiveLimits>) -> ActiveLimits {
+        let current = current.cloned().unwrap_or_default();
+        ActiveLimits {
+            sessions: self.max_sessions.map(|max| {
+                current
+                    .sessions
+                    .unwrap_or_else(|| Arc::new(SessionLimit::new(max)))
+            }),
+            clients: self.max_sessions_per_client.map(|max| {
+                current
+                    .clients
+                    .unwrap_or_else(|| Arc::new(ClientLimit::new(max)))
+            }),
+        }
+    }
+}
+
+/// Limits sessions of a listener are counted in, kept across reloads.
+#[derive(Debug, Clone, Default)]
+pub struct ActiveLimits {
+    sessions: Option<Arc<SessionLimit>>,
+    clients: Option<Arc<ClientLimit>>,
+}
+
+impl ActiveLimits {
+    /// Resizes the limits to the maximums of `limits`, which they are activated by.
+    pub fn resize(&self, limits: &Limits) {
+        if let (Some(limit), Some(max)) = (&self.sessions, limits.max_sessions) {
+            limit.resize(max);
+        }
+        if let (Some(limit), Some(max)) = (&self.clients, limits.max_sessions_per_client) {
+            limit.resize(max);
+        }
+    }
+}
+
+fn duration<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    let s = String::deserialize(d)?;
+    humantime::parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+fn optional_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    duration(d).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, DialError};
+
+    #[test]
+    fn test_parse() {
+        let config: Config = toml::from_str(
+            r#"
+            [upstreams]
+            corp = "http://proxy.corp.example:3128"
+
+            [[listener]]
+            listen = ["127.0.0.1:8080"]
+            provider = "http"
+            proxy_protocol = ["10.0.0.0/8"]
+            forwarded = true
+            identify_clients = true
+
+            [listener.auth.users]
+            alice = "secret"
+
+            [listener.limits]
+            max_sessions = 10
+            max_sessions_per_client = 2
+
+            [listener.socket]
+            reuse_port = 4
+
+            [[listener]]
+            listen = ["127.0.0.1:1080", "[::1]:1080", "unix:/run/juno.sock"]
+            provider = "socks"
+
+            [listener.socket]
+            mode = 0o660
+
+            [listener.dialer]
+            connect_timeout = "5s"
+            dns = ["1.1.1.1"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.upstreams.len(), 1);
+        assert_eq!(config.listeners.len(), 2);
+
+        let http = &config.listeners[0];
+        assert_eq!(http.provider, "http");
+        assert!(http.auth.as_ref().unwrap().verify("alice", "secret"));
+        assert_eq!(http.limits.max_sessions, NonZeroUsize::new(10));
+        assert_eq!(http.limits.max_sessions_per_client, NonZeroUsize::new(2));
+        assert_eq!(http.dialer.retry_backoff, Duration::from_millis(100));
+        assert_eq!(
+            http.proxy_protocol,
+            ["10.0.0.0/8".parse::<IpNet>().unwrap()]
+        );
+        assert!(http.forwarded);
+        assert!(http.identify_clients);
+        assert_eq!(http.socket.reuse_port, NonZeroUsize::new(4));
+
+        let socks = &config.listeners[1];
+        assert_eq!(socks.listen.len(), 3);
+        assert_eq!(socks.socket.mode, Some(0o660));
+        assert_eq!(socks.socket.reuse_port, None);
+        assert!(socks.auth.is_none());
+        assert!(!socks.forwarded);
+        assert!(!socks.identify_clients);
+        assert_eq!(socks.dialer.connect_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(socks.dialer.dns.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let parse = |s: &str| toml::from_str::<Config>(s);
+        assert!(parse("[[listener]]\nlisten = [\"127.0.0.1:80\"]").is_err());
+        assert!(parse("[[listener]]\nprovider = \"http\"\nport = 80").is_err());
+        assert!(
+            parse("[[listener]]\nprovider = \"http\"\n[listener.socket]\nreuse_port = 0").is_err()
+        );
+        assert!(
+            parse("[[listener]]\nprovider = \"http\"\n[listener.limits]\nmax_sessions = 0")
+                .is_err()
+        );
+        assert!(parse(
+            "[[listener]]\nprovider = \"http\"\n[listener.limits]\nmax_sessions_per_client = 0"
+        )
+        .is_err());
+        assert!(parse(
+            "[[listener]]\nprovider = \"http\"\n[listener.dialer]\nretry_backoff = \"soon\""
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_service() {
+        let listener: ListenerConfig = toml::from_str("provider = \"gopher\"").unwrap();
+        let limits = ActiveLimits::default();
+        let res = listener
+            .service(&Registry::default(), &HashMap::new(), &limits)
+            .await;
+        assert!(res.is_err());
+
+        let listener: ListenerConfig = toml::from_str("provider = \"socks\"").unwrap();
+        let upstreams = HashMap::from([(
+            "corp".to_string(),
+            "socks5://127.0.0.1:1080".parse().unwrap(),
+        )]);
+        assert!(listener
+            .service(&Registry::default(), &upstreams, &limits)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let limits: Limits =
+            toml::from_str("max_sessions = 2\nmax_sessions_per_client = 1").unwrap();
+        let active = limits.activate(None);
+        let limit = active.sessions.as_ref().unwrap();
+        let _permit = limit.acquire().await;
+
+        // reuses the limits of the earlier load, in which sessions keep counting
+        let limits: Limits = toml::from_str("max_sessions = 3").unwrap();
+        let reloaded = limits.activate(Some(&active));
+        assert!(Arc::ptr_eq(
+            reloaded.sessions.as_ref().unwrap(),
+            active.sessions.as_ref().unwrap()
+        ));
+        assert!(reloaded.clients.is_none());
+        assert_eq!(limit.available_permits(), 1);
+        reloaded.resize(&limits);
+        assert_eq!(limit.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_dialer() {
+        let dialer: DialerConfig =
+            toml::from_str("allow_destinations = [\"10.1.0.0/16\"]").unwrap();
+        assert!(dialer.build().await.is_ok());
+
+        let dialer: DialerConfig = toml::from_str(
+            "allow_private_destinations = true\nallow_destinations = [\"10.1.0.0/16\"]",
+        )
+        .unwrap();
+        assert!(dialer.build().await.is_err());
+
+        // private destinations are denied unless allowed
+        let addr = Address::new("127.0.0.1", 1);
+        let dialer = DialerConfig::default().build().await.unwrap();
+        assert!(matches!(
+            dialer.dial(&addr).await,
+            Err(DialError::Forbidden(_))
+        ));
+        let dialer: DialerConfig = toml::from_str("allow_private_destinations = true").unwrap();
+        let dialer = dialer.build().await.unwrap();
+        assert!(!matches!(
+            dialer.dial(&addr).await,
+            Err(DialError::Forbidden(_))
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+use crate::route::Context;
+use crate::{Address, DialError};
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Bidirectional byte stream of a connection.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Io for T {}
+
+pub type BoxIo = Box<dyn Io>;
+
+/// Transport establishing outbound connections on behalf of sessions.
+pub trait Connector: Send + Sync {
+    /// Connects to `addr` for the session described by `ctx`.
+    fn connect<'a>(
+        &'a self,
+        ctx: &'a Context,
+        addr: &'a Address,
+    ) -> BoxFuture<'a, Result<BoxIo, DialError>>;
+}
+
+/// Connects to a Unix domain socket at a fixed path, regardless of the destination.
+///
+/// Useful as the transport of an upstream proxy listening on a Unix domain socket.
+#[cfg(unix)]
+pub struct UnixConnector {
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixConnector {
+    pub fn new(path: impl Into<std::path::PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[cfg(unix)]
+impl Connector for UnixConnector {
+    fn connect<'a>(
+        &'a self,
+        _: &'a Context,
+        _: &'a Address,
+    ) -> BoxFuture<'a, Result<BoxIo, DialError>> {
+        use futures::prelude::*;
+
+        tokio::net::UnixStream::connect(&self.path)
+            .map_ok(|s| Box::new(s) as BoxIo)
+            .err_into()
+            .boxed()
+    }
+}
+
+/// Connects to the far end of an in-memory stream, for testing.
+#[cfg(test)]
+pub(crate) struct Duplex(std::sync::Mutex<Option<tokio::io::DuplexStream>>);
+
+#[cfg(test)]
+impl Duplex {
+    /// Creates a connector and the far end of the stream it connects to.
+    pub fn new() -> (Self, tokio::io::DuplexStream) {
+        let (near, far) = tokio::io::duplex(1024);
+        (Self(std::sync::Mutex::new(Some(near))), far)
+    }
+}
+
+#[cfg(test)]
+impl Connector for Duplex {
+    fn connect<'a>(
+        &'a self,
+        _: &'a Context,
+        _: &'a Address,
+    ) -> BoxFuture<'a, Result<BoxIo, DialError>> {
+        let stream = self.0.lock().unwrap().take().unwrap();
+        Box::pin(async move { Ok(Box::new(stream) as BoxIo) })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn test_unix_connector() {
+        let path = std::env::temp_dir().join(format!("juno-connector-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        let connector = UnixConnector::new(&path);
+        let mut stream = connector
+            .connect(&Context::default(), &Address::new("example.com", 80))
+            .await
+            .unwrap();
+
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "hello");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
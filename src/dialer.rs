@@ -0,0 +1,452 @@
+use crate::connector::{BoxIo, Connector};
+use crate::proxy_protocol::{self, Version};
+use crate::resolver::{Resolver, SystemResolver};
+use crate::route::Context;
+use crate::{Address, Filter, Hosts};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+use tokio::time::{sleep, timeout};
+use tracing::debug;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("connection timed out")]
+    TimedOut,
+
+    #[error("connection not allowed by ruleset")]
+    Rejected,
+
+    #[error("destination {0} is not allowed")]
+    Forbidden(SocketAddr),
+
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+/// Retry policy applied when every address of a destination failed to connect.
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    /// Number of retries after the first attempt.
+    pub attempts: u32,
+
+    /// Delay before the first retry, doubled on each subsequent retry.
+    pub backoff: Duration,
+
+    /// Upper bound of the delay between retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Retry {
+    fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .checked_mul(1 << retry.min(31))
+            .unwrap_or(Duration::MAX)
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Clone)]
+pub struct Dialer {
+    hosts: Arc<Hosts>,
+    resolver: Arc<dyn Resolver>,
+    filter: Option<Arc<Filter>>,
+    bind_addr: Option<SocketAddr>,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    retry: Retry,
+    proxy_protocol: Option<Version>,
+}
+
+impl Default for Dialer {
+    fn default() -> Self {
+        Self {
+            hosts: Arc::default(),
+            resolver: Arc::new(SystemResolver),
+            filter: None,
+            bind_addr: None,
+            connect_timeout: None,
+            timeout: None,
+            retry: Retry::default(),
+            proxy_protocol: None,
+        }
+    }
+}
+
+impl Dialer {
+    pub async fn bind(addr: impl AsRef<str>) -> io::Result<Self> {
+        let bind_addr = lookup_host((addr.as_ref(), 0))
+            .await?
+            .next()
+            .ok_or(io::ErrorKind::AddrNotAvailable)?;
+
+        Ok(Self {
+            bind_addr: Some(bind_addr),
+            ..Default::default()
+        })
+    }
+
+    /// Binds outbound connections to `addr`.
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.bind_addr = Some(addr);
+        self
+    }
+
+    /// Overrides destinations before resolving.
+    pub fn hosts(mut self, hosts: Arc<Hosts>) -> Self {
+        self.hosts = hosts;
+        self
+    }
+
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Restricts the addresses connected to after resolution.
+    pub fn filter(mut self, filter: Option<Arc<Filter>>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Limits the time spent on each connection attempt to a single address.
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Limits the time spent on a whole dial, including resolution and retries.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the limit of the time to establish a connection, if any, which handshakes with
+    /// upstream proxies reached by the dialer are limited to as well.
+    pub(crate) fn deadline(&self) -> Option<Duration> {
+        self.timeout.or(self.connect_timeout)
+    }
+
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sends a PROXY protocol header on connections made as [`Connector`], telling the address of
+    /// the client.
+    pub fn proxy_protocol(mut self, version: Option<Version>) -> Self {
+        self.proxy_protocol = version;
+        self
+    }
+
+    pub async fn dial(&self, addr: &Address) -> Result<TcpStream, Error> {
+        self.with_timeout(self.dial_all(addr, None)).await
+    }
+
+    /// Limits the whole `connect` to the timeout.
+    async fn with_timeout<T>(
+        &self,
+        connect: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        match self.timeout {
+            Some(limit) => timeout(limit, connect)
+                .await
+                .unwrap_or(Err(Error::TimedOut)),
+            None => connect.await,
+        }
+    }
+
+    /// Resolves `addr`, overridden by the hosts if any, into the addresses it connects to.
+    pub(crate) async fn resolve(&self, addr: &Address) -> io::Result<Vec<SocketAddr>> {
+        let mapped = self.hosts.map(addr);
+        if let Some(mapped) = &mapped {
+            debug!("mapped {addr} to {mapped}");
+        }
+
+        match mapped.as_ref().unwrap_or(addr) {
+            Address::Ip(addr) => Ok(vec![*addr]),
+            Address::Domain(host, port) => self.resolver.resolve(host, *port).await,
+        }
+    }
+
+    /// Dials `addr` by `addrs` it resolved to beforehand, or resolves it first if `None`.
+    async fn dial_all(
+        &self,
+        addr: &Address,
+        addrs: Option<Vec<SocketAddr>>,
+    ) -> Result<TcpStream, Error> {
+        let mut addrs = match addrs {
+            Some(addrs) => addrs,
+            None => self.resolve(addr).await?,
+        };
+        if let Some(filter) = &self.filter {
+            let (allowed, denied) = addrs
+                .into_iter()
+                .partition::<Vec<_>, _>(|a| filter.is_allowed(a.ip()));
+            match (allowed.is_empty(), denied.first()) {
+                (true, Some(&denied)) => return Err(Error::Forbidden(denied)),
+                (false, Some(_)) => debug!("{addr} resolved to denied addresses {denied:?}"),
+                _ => {}
+            }
+            addrs = allowed;
+        }
+        if addrs.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::AddrNotAvailable).into());
+        }
+
+        self.with_retry(|| {
+            let dials = addrs.iter().map(|&addr| self.dial_one(addr).boxed());
+            future::select_ok(dials).map_ok(|(stream, _)| stream)
+        })
+        .await
+    }
+
+    /// Makes attempts with `attempt` until one succeeds or the retries run out.
+    async fn with_retry<T, F>(&self, mut attempt: impl FnMut() -> F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let mut retry = 0;
+        loop {
+            let err = match attempt().await {
+                Ok(conn) => return Ok(conn),
+                Err(e) => e,
+            };
+
+            if retry >= self.retry.attempts {
+                return Err(err);
+            }
+
+            let delay = self.retry.delay(retry);
+            debug!("failed to connect: {err}, retrying in {delay:?}");
+            sleep(delay).await;
+            retry += 1;
+        }
+    }
+
+    async fn dial_one(&self, addr: SocketAddr) -> Result<TcpStream, Error> {
+        let sock = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4(),
+            SocketAddr::V6(_) => TcpSocket::new_v6(),
+        }?;
+
+        if let Some(addr) = self.bind_addr {
+            sock.bind(addr)?;
+        }
+
+        self.attempt(sock.connect(addr)).await
+    }
+
+    /// Limits a single connection attempt `connect` to the connect timeout.
+    async fn attempt<T>(&self, connect: impl Future<Output = io::Result<T>>) -> Result<T, Error> {
+        match self.connect_timeout {
+            Some(limit) => match timeout(limit, connect).await {
+                Ok(r) => Ok(r?),
+                Err(_) => Err(Error::TimedOut),
+            },
+            None => Ok(connect.await?),
+        }
+    }
+
+    /// Connects as [`Connector`] does, by `addrs` of `addr` resolved beforehand if any.
+    pub(crate) async fn connect_resolved(
+        &self,
+        ctx: &Context,
+        addr: &Address,
+        addrs: Option<Vec<SocketAddr>>,
+    ) -> Result<BoxIo, Error> {
+        let connect = async {
+            let mut stream = self.dial_all(addr, addrs).await?;
+            if let Some(version) = self.proxy_protocol {
+                let header = proxy_protocol::encode(version, ctx.peer_addr, stream.peer_addr()?);
+                self.send_header(&mut stream, &header).await?;
+            }
+            Ok(stream)
+        };
+        Ok(Box::new(self.with_timeout(connect).await?))
+    }
+
+    /// Sends the PROXY protocol `header` on `stream` as a connection attempt, which the
+    /// destination may never read.
+    async fn send_header(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        header: &[u8],
+    ) -> Result<(), Error> {
+        self.attempt(stream.write_all(header)).await
+    }
+}
+
+impl Connector for Dialer {
+    fn connect<'a>(
+        &'a self,
+        ctx: &'a Context,
+        addr: &'a Address,
+    ) -> BoxFuture<'a, Result<BoxIo, Error>> {
+        self.connect_resolved(ctx, addr, None).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = Retry {
+            attempts: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        };
+
+        assert_eq!(retry.delay(0), Duration::from_millis(100));
+        assert_eq!(retry.delay(1), Duration::from_millis(200));
+        assert_eq!(retry.delay(2), Duration::from_millis(400));
+        assert_eq!(retry.delay(3), Duration::from_millis(500));
+        assert_eq!(retry.delay(100), Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_dial_refused() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let dialer = Arc::new(Dialer::default().retry(Retry {
+            attempts: 2,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        }));
+        assert!(matches!(dialer.dial(&addr.into()).await, Err(Error::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn test_dial_filter() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let filter = Filter::default();
+        let dialer = Arc::new(Dialer::default().filter(Some(Arc::new(filter.clone()))));
+        assert!(matches!(
+            dialer.dial(&addr.into()).await,
+            Err(Error::Forbidden(a)) if a == addr
+        ));
+
+        let nat64 = SocketAddr::new("64:ff9b::7f00:1".parse().unwrap(), addr.port());
+        assert!(matches!(
+            dialer.dial(&nat64.into()).await,
+            Err(Error::Forbidden(a)) if a == nat64
+        ));
+
+        let filter = filter.allow("127.0.0.1/32".parse().unwrap());
+        let dialer = Arc::new(Dialer::default().filter(Some(Arc::new(filter))));
+        assert!(dialer.dial(&addr.into()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let ctx = Context {
+            peer_addr: Some("192.0.2.1:56324".parse().unwrap()),
+            ..Default::default()
+        };
+        let dialer = Dialer::default().proxy_protocol(Some(Version::V2));
+        let _stream = dialer.connect(&ctx, &addr.into()).await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let header = proxy_protocol::read_header(&mut stream).await.unwrap();
+        assert_eq!(header, Some((ctx.peer_addr.unwrap(), addr)));
+    }
+
+    /// Resolver never answering, standing for a destination that never responds.
+    struct Unresponsive;
+
+    impl Resolver for Unresponsive {
+        fn resolve<'a>(&'a self, _: &'a str, _: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+            future::pending().boxed()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dial_timeout() {
+        let limit = Duration::from_secs(10);
+        let dialer = Dialer::default()
+            .resolver(Arc::new(Unresponsive))
+            .timeout(Some(limit));
+
+        let start = tokio::time::Instant::now();
+        assert!(matches!(
+            dialer.dial(&Address::new("example.com", 80)).await,
+            Err(Error::TimedOut)
+        ));
+        assert!(start.elapsed() >= limit);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_timeout() {
+        let limit = Duration::from_secs(5);
+        let dialer = Dialer::default().connect_timeout(Some(limit));
+
+        // an attempt never completing times out on its own
+        let start = tokio::time::Instant::now();
+        let res = dialer.attempt(future::pending::<io::Result<()>>()).await;
+        assert!(matches!(res, Err(Error::TimedOut)));
+        assert_eq!(start.elapsed(), limit);
+
+        // each retry is given the whole timeout again
+        let dialer = dialer.retry(Retry {
+            attempts: 2,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        });
+        let mut attempts = 0;
+        let start = tokio::time::Instant::now();
+        let res = dialer
+            .with_retry(|| {
+                attempts += 1;
+                dialer.attempt(future::pending::<io::Result<()>>())
+            })
+            .await;
+        assert!(matches!(res, Err(Error::TimedOut)));
+        assert_eq!(attempts, 3);
+        assert_eq!(start.elapsed(), limit * 3 + Duration::from_secs(1 + 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_header_timeout() {
+        let limit = Duration::from_secs(5);
+        let dialer = Dialer::default().connect_timeout(Some(limit));
+
+        // the destination never reads, leaving room for only a byte of the header
+        let (mut stream, _destination) = io::duplex(1);
+        let header = proxy_protocol::encode(
+            Version::V1,
+            Some("192.0.2.1:10000".parse().unwrap()),
+            "198.51.100.1:80".parse().unwrap(),
+        );
+        let start = tokio::time::Instant::now();
+        let res = dialer.send_header(&mut stream, &header).await;
+        assert!(matches!(res, Err(Error::TimedOut)));
+        assert_eq!(start.elapsed(), limit);
+    }
+}
@@ -0,0 +1,107 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Ranges denied by default: unspecified, loopback, private, shared, link-local, documentation,
+/// benchmarking, multicast and reserved addresses, and the NAT64, 6to4 and Teredo prefixes, which
+/// embed IPv4 addresses.
+const DEFAULT_DENY: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "64:ff9b:1::/48",
+    "100::/64",
+    "2001::/32",
+    "2001:db8::/32",
+    "2002::/16",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Filter of destination addresses, applied to the exact addresses connected to.
+///
+/// Addresses in the allowed ranges always pass, others are denied if they are in any of the
+/// denied ranges. IPv4-mapped IPv6 addresses are checked as IPv4 addresses.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    deny: Vec<IpNet>,
+    allow: Vec<IpNet>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            deny: DEFAULT_DENY.iter().map(|s| s.parse().unwrap()).collect(),
+            allow: vec![],
+        }
+    }
+}
+
+impl Filter {
+    /// Creates a filter without any denied ranges.
+    pub fn empty() -> Self {
+        Self {
+            deny: vec![],
+            allow: vec![],
+        }
+    }
+
+    /// Adds a denied range.
+    pub fn deny(mut self, net: IpNet) -> Self {
+        self.deny.push(net);
+        self
+    }
+
+    /// Adds an exception to the denied ranges.
+    pub fn allow(mut self, net: IpNet) -> Self {
+        self.allow.push(net);
+        self
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.allow.iter().any(|n| n.contains(&ip)) || !self.deny.iter().any(|n| n.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed() {
+        let filter = Filter::default()
+            .deny("93.184.215.0/24".parse().unwrap())
+            .allow("10.1.0.0/16".parse().unwrap());
+
+        let allowed = |s: &str| filter.is_allowed(s.parse().unwrap());
+        assert!(!allowed("127.0.0.1"));
+        assert!(!allowed("169.254.169.254"));
+        assert!(!allowed("192.168.1.1"));
+        assert!(!allowed("10.0.0.1"));
+        assert!(!allowed("::1"));
+        assert!(!allowed("::ffff:127.0.0.1"));
+        assert!(!allowed("fd00::1"));
+        assert!(!allowed("64:ff9b::7f00:1"));
+        assert!(!allowed("2002:c0a8:101::1"));
+        assert!(!allowed("2001:0:4136:e378:8000:63bf:3fff:fdd2"));
+        assert!(!allowed("93.184.215.14"));
+        assert!(allowed("10.1.2.3"));
+        assert!(allowed("1.1.1.1"));
+        assert!(allowed("2606:4700::1111"));
+    }
+}
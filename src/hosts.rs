@@ -0,0 +1,259 @@
+use crate::Address;
+use anyhow::{anyhow, bail, Context as _, Result};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Static overrides of destinations, consulted before resolving.
+///
+/// Each line of the source is either a hosts(5) style entry, which maps names to an address, or a
+/// rewrite rule, which replaces the destination with another one:
+///
+/// ```text
+/// # <address> <pattern>...
+/// 192.0.2.10 staging.example.com *.staging.example.com
+///
+/// # <pattern>[:<port>] -> <host>[:<port>]
+/// old.internal:80 -> new.internal:8080
+/// *.legacy.example -> modern.example
+/// ```
+///
+/// A pattern is either an exact name or a wildcard suffix starting with `*.`. When several rules
+/// match, exact names are preferred over wildcards, longer suffixes over shorter ones, and rules
+/// with a port over those without.
+#[derive(Debug, Default)]
+pub struct Hosts {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Pattern,
+    port: Option<u16>,
+    target: Target,
+}
+
+#[derive(Debug)]
+enum Pattern {
+    Exact(String),
+    Suffix(String),
+}
+
+#[derive(Debug)]
+enum Target {
+    Ip(IpAddr),
+    Rewrite(String, Option<u16>),
+}
+
+impl Hosts {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .parse()
+            .with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    /// Returns the destination `addr` is mapped to, if any.
+    pub fn map(&self, addr: &Address) -> Option<Address> {
+        let (host, port) = match addr {
+            Address::Ip(addr) => (addr.ip().to_string(), addr.port()),
+            Address::Domain(domain, port) => (domain.to_ascii_lowercase(), *port),
+        };
+        let host = host.trim_end_matches('.');
+
+        let rule = self
+            .rules
+            .iter()
+            .filter(|r| r.port.is_none_or(|p| p == port))
+            .filter_map(|r| Some((r.pattern.specificity(host)?, r)))
+            .max_by_key(|&((exact, len), r)| (exact, len, r.port.is_some()))?
+            .1;
+
+        Some(match &rule.target {
+            Target::Ip(ip) => Address::Ip((*ip, port).into()),
+            Target::Rewrite(host, new_port) => Address::new(host, new_port.unwrap_or(port)),
+        })
+    }
+}
+
+impl FromStr for Hosts {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut rules = vec![];
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(l, _)| l).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            Rule::parse(line, &mut rules).with_context(|| format!("line {}", i + 1))?;
+        }
+
+        Ok(Self { rules })
+    }
+}
+
+impl Rule {
+    fn parse(line: &str, rules: &mut Vec<Rule>) -> Result<()> {
+        if let Some((from, to)) = line.split_once("->") {
+            let (pattern, port) = split_host_port(from.trim())?;
+            let (host, new_port) = split_host_port(to.trim())?;
+            if host.starts_with("*.") {
+                bail!("wildcard is not allowed in target `{host}`");
+            }
+
+            rules.push(Rule {
+                pattern: pattern.parse()?,
+                port,
+                target: Target::Rewrite(host.to_string(), new_port),
+            });
+        } else {
+            let mut fields = line.split_whitespace();
+            let ip = fields.next().unwrap_or_default();
+            let ip = ip
+                .parse::<IpAddr>()
+                .with_context(|| format!("invalid address `{ip}`"))?;
+
+            let mut names = fields.peekable();
+            if names.peek().is_none() {
+                bail!("no names for `{ip}`");
+            }
+            for name in names {
+                rules.push(Rule {
+                    pattern: name.parse()?,
+                    port: None,
+                    target: Target::Ip(ip),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Pattern {
+    fn specificity(&self, host: &str) -> Option<(bool, usize)> {
+        match self {
+            Self::Exact(name) if name == host => Some((true, name.len())),
+            Self::Suffix(suffix)
+                if host.len() > suffix.len()
+                    && host.ends_with(suffix.as_str())
+                    && host.as_bytes()[host.len() - suffix.len() - 1] == b'.' =>
+            {
+                Some((false, suffix.len()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim_end_matches('.').to_ascii_lowercase();
+        match s.strip_prefix("*.") {
+            Some(suffix) if suffix.is_empty() || suffix.contains('*') => {
+                Err(anyhow!("invalid pattern `{s}`"))
+            }
+            Some(suffix) => Ok(Self::Suffix(suffix.to_string())),
+            None if s.is_empty() || s.contains('*') => Err(anyhow!("invalid pattern `{s}`")),
+            None => Ok(Self::Exact(s)),
+        }
+    }
+}
+
+fn split_host_port(s: &str) -> Result<(&str, Option<u16>)> {
+    let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+        let (ip, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| anyhow!("invalid address `{s}`"))?;
+        match rest.strip_prefix(':') {
+            Some(port) => (ip, Some(port)),
+            None if rest.is_empty() => (ip, None),
+            None => bail!("invalid address `{s}`"),
+        }
+    } else if s.matches(':').count() == 1 {
+        let (host, port) = s.split_once(':').unwrap();
+        (host, Some(port))
+    } else {
+        (s, None)
+    };
+
+    let port = port
+        .map(|p| p.parse().with_context(|| format!("invalid port `{p}`")))
+        .transpose()?;
+    Ok((host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(hosts: &Hosts, addr: &str) -> Option<String> {
+        let (host, port) = split_host_port(addr).unwrap();
+        hosts
+            .map(&Address::new(host, port.unwrap()))
+            .map(|a| a.to_string())
+    }
+
+    #[test]
+    fn test_map() {
+        let hosts = r"
+            # comment
+            192.0.2.10 staging.example.com *.staging.example.com
+            2001:db8::1 v6.example.com # trailing comment
+            old.internal:80 -> new.internal:8080
+            *.legacy.example -> modern.example
+            a.legacy.example:443 -> [2001:db8::2]
+            192.0.2.1:22 -> bastion.internal
+        "
+        .parse::<Hosts>()
+        .unwrap();
+
+        assert_eq!(
+            map(&hosts, "staging.example.com:443").as_deref(),
+            Some("192.0.2.10:443")
+        );
+        assert_eq!(
+            map(&hosts, "www.Staging.Example.com.:80").as_deref(),
+            Some("192.0.2.10:80")
+        );
+        assert_eq!(
+            map(&hosts, "v6.example.com:80").as_deref(),
+            Some("[2001:db8::1]:80")
+        );
+        assert_eq!(
+            map(&hosts, "old.internal:80").as_deref(),
+            Some("new.internal:8080")
+        );
+        assert_eq!(map(&hosts, "old.internal:81"), None);
+        assert_eq!(
+            map(&hosts, "a.legacy.example:80").as_deref(),
+            Some("modern.example:80")
+        );
+        assert_eq!(
+            map(&hosts, "a.legacy.example:443").as_deref(),
+            Some("[2001:db8::2]:443")
+        );
+        assert_eq!(map(&hosts, "legacy.example:80"), None);
+        assert_eq!(map(&hosts, "xlegacy.example:80"), None);
+        assert_eq!(
+            map(&hosts, "192.0.2.1:22").as_deref(),
+            Some("bastion.internal:22")
+        );
+        assert_eq!(map(&hosts, "example.com:80"), None);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!("example.com".parse::<Hosts>().is_err());
+        assert!("192.0.2.1".parse::<Hosts>().is_err());
+        assert!("a.example -> *.example".parse::<Hosts>().is_err());
+        assert!("a.example:http -> b.example".parse::<Hosts>().is_err());
+        assert!("192.0.2.1 *.".parse::<Hosts>().is_err());
+    }
+}
@@ -1,29 +1,76 @@
-use crate::Dialer;
+use crate::connector::Io;
+use crate::limit::REJECT_TIMEOUT;
+use crate::route::Context;
+use crate::{Address, Auth, ClientLimit, Connection, Connector, DialError};
+use base64::prelude::*;
 use future::BoxFuture;
 use futures::prelude::*;
 use hyper::client::conn::Builder;
-use hyper::header::{HeaderName, PROXY_AUTHORIZATION};
+use hyper::header::{
+    HeaderName, HeaderValue, CONNECTION, FORWARDED, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION,
+};
 use hyper::server::conn::Http;
 use hyper::{Body, Method, Request, Response, StatusCode};
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::task;
-use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 use tracing::error;
 
 #[derive(Clone)]
 pub struct Service {
-    dialer: Arc<Dialer>,
+    connector: Arc<dyn Connector>,
+    auth: Option<Arc<Auth>>,
+    client_limit: Option<Arc<ClientLimit>>,
+    forwarded: bool,
 }
 
 impl Service {
-    pub fn new(dialer: Dialer) -> Self {
+    pub fn new(connector: Arc<dyn Connector>) -> Self {
         Self {
-            dialer: Arc::new(dialer),
+            connector,
+            auth: None,
+            client_limit: None,
+            forwarded: false,
         }
     }
+
+    /// Requires clients to authenticate with the Basic scheme.
+    pub fn auth(mut self, auth: Option<Arc<Auth>>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Responds with 503 to clients over `limit`.
+    pub fn client_limit(mut self, limit: Option<Arc<ClientLimit>>) -> Self {
+        self.client_limit = limit;
+        self
+    }
+
+    /// Adds the addresses of clients to requests in `Forwarded` headers.
+    pub fn forwarded(mut self, forwarded: bool) -> Self {
+        self.forwarded = forwarded;
+        self
+    }
+
+    fn reject<S: Io>(conn: Connection<S>) -> BoxFuture<'static, anyhow::Result<()>> {
+        let service = tower::service_fn(|_| async {
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(CONNECTION, "close")
+                .body(Body::from("too many sessions"))
+        });
+
+        let serve = Http::new().serve_connection(conn.io, service);
+        async move {
+            tokio::time::timeout(REJECT_TIMEOUT, serve).await??;
+            Ok(())
+        }
+        .boxed()
+    }
 }
 
-impl tower::Service<TcpStream> for Service {
+impl<S: Io> tower::Service<Connection<S>> for Service {
     type Response = ();
     type Error = anyhow::Error;
     type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
@@ -32,37 +79,110 @@ impl tower::Service<TcpStream> for Service {
         task::Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, stream: TcpStream) -> Self::Future {
-        Http::new()
+    fn call(&mut self, conn: Connection<S>) -> Self::Future {
+        let permit = match &self.client_limit {
+            Some(limit) => match limit.acquire(&conn.meta) {
+                Some(permit) => Some(permit),
+                None => return Self::reject(conn),
+            },
+            None => None,
+        };
+        let ctx = Context::new("http", &conn.meta);
+        let (tunnels_tx, mut tunnels_rx) = mpsc::unbounded_channel();
+        let session = Session::new(Arc::clone(&self.connector), ctx, tunnels_tx)
+            .auth(self.auth.clone())
+            .forwarded(self.forwarded);
+
+        let serve = Http::new()
             .http1_preserve_header_case(true)
             .http1_title_case_headers(true)
-            .serve_connection(stream, Session::new(&self.dialer))
-            .with_upgrades()
-            .err_into()
-            .boxed()
+            .serve_connection(conn.io, session)
+            .with_upgrades();
+
+        async move {
+            serve.await?;
+
+            // the connection is handed over to the tunnel on CONNECT, which is part of the session
+            while let Some(tunnel) = tunnels_rx.recv().await {
+                tunnel.await;
+            }
+            drop(permit);
+            Ok(())
+        }
+        .boxed()
     }
 }
 
-#[cfg_attr(test, derive(Default))]
 struct Session {
-    dialer: Arc<Dialer>,
+    connector: Arc<dyn Connector>,
+    auth: Option<Arc<Auth>>,
+    forwarded: bool,
+    ctx: Arc<Context>,
+
+    /// Tunnels established on CONNECT, run by the service after the connection is upgraded.
+    tunnels: mpsc::UnboundedSender<BoxFuture<'static, ()>>,
 }
 
 impl Session {
-    fn new(dialer: &Arc<Dialer>) -> Self {
+    fn new(
+        connector: Arc<dyn Connector>,
+        ctx: Context,
+        tunnels: mpsc::UnboundedSender<BoxFuture<'static, ()>>,
+    ) -> Self {
         Self {
-            dialer: Arc::clone(dialer),
+            connector,
+            auth: None,
+            forwarded: false,
+            ctx: Arc::new(ctx),
+            tunnels,
         }
     }
 
+    fn auth(mut self, auth: Option<Arc<Auth>>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    fn forwarded(mut self, forwarded: bool) -> Self {
+        self.forwarded = forwarded;
+        self
+    }
+
+    /// Returns the context of the request, or `None` if the client failed to authenticate.
+    fn authenticate<T>(&self, req: &Request<T>) -> Option<Arc<Context>> {
+        let Some(auth) = &self.auth else {
+            return Some(Arc::clone(&self.ctx));
+        };
+
+        let user = req
+            .headers()
+            .get(PROXY_AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Basic "))
+            .and_then(|v| BASE64_STANDARD.decode(v.trim()).ok())
+            .and_then(|v| String::from_utf8(v).ok())
+            .and_then(|v| {
+                let (user, password) = v.split_once(':')?;
+                auth.verify(user, password).then(|| user.to_string())
+            });
+
+        Some(Arc::new(Context {
+            user: Some(user?),
+            ..Context::clone(&self.ctx)
+        }))
+    }
+
     fn handle_connect(
         &self,
         req: Request<Body>,
+        ctx: Arc<Context>,
     ) -> impl Future<Output = Result<Response<Body>, hyper::Error>> {
-        let res = if let Some(authority) = req.uri().authority() {
-            let addr = authority.to_string();
-            let dialer = Arc::clone(&self.dialer);
-            Ok((addr, dialer))
+        let res = if let Some((authority, port)) =
+            req.uri().authority().and_then(|a| Some((a, a.port_u16()?)))
+        {
+            let addr = Address::new(authority.host(), port);
+            let connector = Arc::clone(&self.connector);
+            Ok((addr, connector, ctx, self.tunnels.clone()))
         } else {
             Err(Response::builder()
                 .status(StatusCode::BAD_REQUEST)
@@ -71,22 +191,17 @@ impl Session {
         };
 
         async move {
-            let (addr, dialer) = match res {
+            let (addr, connector, ctx, tunnels) = match res {
                 Ok(req) => req,
                 Err(res) => return Ok(res),
             };
 
-            let mut server = match dialer.dial(addr).await {
+            let mut server = match connector.connect(&ctx, &addr).await {
                 Ok(server) => server,
-                Err(e) => {
-                    return Ok(Response::builder()
-                        .status(StatusCode::BAD_GATEWAY)
-                        .body(Body::from(e.to_string()))
-                        .unwrap());
-                }
+                Err(e) => return Ok(Self::dial_error(e)),
             };
 
-            tokio::task::spawn(async move {
+            let tunnel = async move {
                 match hyper::upgrade::on(req).await {
                     Ok(mut client) => {
                         let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
@@ -95,12 +210,28 @@ impl Session {
                         error!("Failed to upgrade: {e}");
                     }
                 };
-            });
+            }
+            .boxed();
+            // fails only if the session is already closed
+            let _ = tunnels.send(tunnel);
 
             Ok(Response::new(Body::empty()))
         }
     }
 
+    fn dial_error(e: DialError) -> Response<Body> {
+        let status = match e {
+            DialError::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+            DialError::Rejected | DialError::Forbidden(_) => StatusCode::FORBIDDEN,
+            DialError::Io(_) => StatusCode::BAD_GATEWAY,
+        };
+
+        Response::builder()
+            .status(status)
+            .body(Body::from(e.to_string()))
+            .unwrap()
+    }
+
     #[allow(clippy::declare_interior_mutable_const)]
     const PROXY_CONNECTION: HeaderName = HeaderName::from_static("proxy-connection");
 
@@ -108,13 +239,24 @@ impl Session {
         *req.uri_mut() = req
             .uri()
             .path_and_query()
-            .map(Clone::clone)
+            .cloned()
             .map(Into::into)
             .unwrap_or_default();
 
         let map = req.headers_mut();
         map.remove(Self::PROXY_CONNECTION);
         map.remove(PROXY_AUTHORIZATION);
+        if self.forwarded {
+            // quotes IPv6 addresses as RFC 7239 requires
+            let value = match self.ctx.peer_addr.map(|a| a.ip().to_canonical()) {
+                Some(IpAddr::V4(ip)) => format!("for={ip}"),
+                Some(IpAddr::V6(ip)) => format!("for=\"[{ip}]\""),
+                None => "for=unknown".to_string(),
+            };
+            if let Ok(value) = HeaderValue::try_from(value) {
+                map.append(FORWARDED, value);
+            }
+        }
 
         req
     }
@@ -122,16 +264,13 @@ impl Session {
     fn handle_request(
         &self,
         req: Request<Body>,
+        ctx: Arc<Context>,
     ) -> impl Future<Output = Result<Response<Body>, hyper::Error>> {
         let res = if let Some(authority) = req.uri().authority() {
-            let addr = format!(
-                "{}:{}",
-                authority.host(),
-                authority.port_u16().unwrap_or(80)
-            );
-            let dialer = Arc::clone(&self.dialer);
+            let addr = Address::new(authority.host(), authority.port_u16().unwrap_or(80));
+            let connector = Arc::clone(&self.connector);
             let req = self.transform_request(req);
-            Ok((addr, dialer, req))
+            Ok((addr, connector, ctx, req))
         } else {
             Err(Response::builder()
                 .status(StatusCode::BAD_REQUEST)
@@ -140,19 +279,14 @@ impl Session {
         };
 
         async move {
-            let (addr, dialer, req) = match res {
+            let (addr, connector, ctx, req) = match res {
                 Ok(req) => req,
                 Err(res) => return Ok(res),
             };
 
-            let stream = match dialer.dial(addr).await {
+            let stream = match connector.connect(&ctx, &addr).await {
                 Ok(stream) => stream,
-                Err(e) => {
-                    return Ok(Response::builder()
-                        .status(StatusCode::BAD_GATEWAY)
-                        .body(Body::from(e.to_string()))
-                        .unwrap());
-                }
+                Err(e) => return Ok(Self::dial_error(e)),
             };
 
             match Builder::new()
@@ -189,10 +323,19 @@ impl tower::Service<Request<Body>> for Session {
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let Some(ctx) = self.authenticate(&req) else {
+            let res = Response::builder()
+                .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+                .header(PROXY_AUTHENTICATE, "Basic realm=\"juno\"")
+                .body(Body::empty())
+                .unwrap();
+            return future::ok(res).boxed();
+        };
+
         if Method::CONNECT == req.method() {
-            self.handle_connect(req).boxed()
+            self.handle_connect(req, ctx).boxed()
         } else {
-            self.handle_request(req).boxed()
+            self.handle_request(req, ctx).boxed()
         }
     }
 }
@@ -200,6 +343,11 @@ impl tower::Service<Request<Body>> for Session {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::connector::Duplex;
+    use crate::inbound::Metadata;
+    use std::num::NonZeroUsize;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tower::Service as _;
 
     #[test]
     fn test() {
@@ -210,8 +358,139 @@ mod tests {
             .body(())
             .unwrap();
 
-        let req = Session::default().transform_request(req);
+        let session = Session::new(
+            Arc::new(crate::Dialer::default()),
+            Context::default(),
+            mpsc::unbounded_channel().0,
+        );
+        let req = session.transform_request(req);
         assert_eq!(req.uri(), "/index.html");
         assert!(!req.headers().contains_key("Proxy-Connection"));
+        assert!(!req.headers().contains_key(FORWARDED));
+
+        let ctx = Context {
+            peer_addr: Some("[2001:db8::1]:10000".parse().unwrap()),
+            ..Default::default()
+        };
+        let session = Session::new(
+            Arc::new(crate::Dialer::default()),
+            ctx,
+            mpsc::unbounded_channel().0,
+        )
+        .forwarded(true);
+        let req = Request::builder()
+            .uri("http://example.org/")
+            .header(FORWARDED, "for=192.0.2.1")
+            .body(())
+            .unwrap();
+        let req = session.transform_request(req);
+        let values: Vec<_> = req.headers().get_all(FORWARDED).iter().collect();
+        assert_eq!(values, ["for=192.0.2.1", "for=\"[2001:db8::1]\""]);
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let (connector, mut server) = Duplex::new();
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let conn = Connection::new(stream, Metadata::default());
+        tokio::spawn(Service::new(Arc::new(connector)).call(conn));
+
+        client
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let len = client.read(&mut buf).await.unwrap();
+        assert!(buf[..len].starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let (connector, mut server) = Duplex::new();
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let conn = Connection::new(stream, Metadata::default());
+        let auth = Auth::default().user("alice", "secret");
+        let service = Service::new(Arc::new(connector)).auth(Some(Arc::new(auth)));
+        tokio::spawn(service.clone().call(conn));
+
+        client
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let len = client.read(&mut buf).await.unwrap();
+        let res = String::from_utf8_lossy(&buf[..len]);
+        assert!(res.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n"));
+        assert!(res.contains("Proxy-Authenticate: Basic realm=\"juno\"\r\n"));
+
+        client
+            .write_all(
+                b"CONNECT example.com:443 HTTP/1.1\r\n\
+                  Host: example.com:443\r\n\
+                  Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let len = client.read(&mut buf).await.unwrap();
+        assert!(buf[..len].starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_client_limit() {
+        let meta = Metadata {
+            peer_addr: Some("192.0.2.1:1000".parse().unwrap()),
+            ..Default::default()
+        };
+        let limit = Arc::new(ClientLimit::new(NonZeroUsize::new(1).unwrap()));
+        let permit = limit.acquire(&meta).unwrap();
+
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let service = Service::new(Arc::new(Duplex::new().0)).client_limit(Some(limit));
+        tokio::spawn(service.clone().call(Connection::new(stream, meta.clone())));
+
+        client
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+
+        drop(permit);
+        let (mut client, stream) = tokio::io::duplex(1024);
+        tokio::spawn(service.clone().call(Connection::new(stream, meta)));
+        client
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let len = client.read(&mut buf).await.unwrap();
+        assert!(buf[..len].starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_limit_timeout() {
+        let meta = Metadata {
+            peer_addr: Some("192.0.2.1:1000".parse().unwrap()),
+            ..Default::default()
+        };
+        let limit = Arc::new(ClientLimit::new(NonZeroUsize::new(1).unwrap()));
+        let _permit = limit.acquire(&meta).unwrap();
+
+        // closes the connection of a client over the limit sending nothing
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let mut service = Service::new(Arc::new(Duplex::new().0)).client_limit(Some(limit));
+        assert!(service.call(Connection::new(stream, meta)).await.is_err());
+        assert_eq!(client.read(&mut [0]).await.unwrap(), 0);
     }
 }
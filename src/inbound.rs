@@ -0,0 +1,128 @@
+use crate::connector::{BoxIo, Io};
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// Properties of an inbound connection.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct Metadata {
+    /// Address of the client.
+    pub peer_addr: Option<SocketAddr>,
+
+    /// Address the connection was accepted on.
+    pub local_addr: Option<SocketAddr>,
+
+    /// Properties of the TLS session, if the connection is secured.
+    pub tls: Option<TlsInfo>,
+
+    /// Credentials of the client process, if connected over a Unix domain socket.
+    pub peer_cred: Option<PeerCred>,
+}
+
+/// Credentials of a local process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PeerCred {
+    pub uid: u32,
+
+    /// Group ID, unknown if the process is not visible.
+    pub gid: Option<u32>,
+
+    /// Process ID, unknown if the platform does not tell it or the process is not visible.
+    pub pid: Option<i32>,
+
+    /// Name of the program the process runs, if known.
+    pub program: Option<String>,
+}
+
+impl PeerCred {
+    /// Creates credentials of a process run by the user `uid`, of which nothing else is known.
+    pub fn new(uid: u32) -> Self {
+        Self {
+            uid,
+            gid: None,
+            pid: None,
+            program: None,
+        }
+    }
+}
+
+impl fmt::Display for PeerCred {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "uid={}", self.uid)?;
+        if let Some(gid) = self.gid {
+            write!(f, " gid={gid}")?;
+        }
+        if let Some(pid) = self.pid {
+            write!(f, " pid={pid}")?;
+        }
+        if let Some(program) = &self.program {
+            write!(f, " program={program}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Properties of a TLS session.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct TlsInfo {
+    /// Server name indicated by the client.
+    pub server_name: Option<String>,
+
+    /// Protocol negotiated by ALPN.
+    pub alpn_protocol: Option<Vec<u8>>,
+}
+
+/// Inbound connection served by providers.
+pub struct Connection<S = BoxIo> {
+    pub io: S,
+    pub meta: Metadata,
+}
+
+impl<S: Io> Connection<S> {
+    pub fn new(io: S, meta: Metadata) -> Self {
+        Self { io, meta }
+    }
+
+    pub fn boxed(self) -> Connection {
+        Connection {
+            io: Box::new(self.io),
+            meta: self.meta,
+        }
+    }
+}
+
+impl From<TcpStream> for Connection<TcpStream> {
+    fn from(stream: TcpStream) -> Self {
+        let meta = Metadata {
+            peer_addr: stream.peer_addr().ok(),
+            local_addr: stream.local_addr().ok(),
+            tls: None,
+            peer_cred: None,
+        };
+
+        Self::new(stream, meta)
+    }
+}
+
+#[cfg(unix)]
+impl From<tokio::net::UnixStream> for Connection<tokio::net::UnixStream> {
+    fn from(stream: tokio::net::UnixStream) -> Self {
+        let meta = Metadata {
+            peer_cred: stream.peer_cred().ok().map(|cred| PeerCred {
+                uid: cred.uid(),
+                gid: Some(cred.gid()),
+                pid: cred.pid(),
+                #[cfg(target_os = "linux")]
+                program: cred.pid().and_then(crate::procfs::program),
+                #[cfg(not(target_os = "linux"))]
+                program: None,
+            }),
+            ..Default::default()
+        };
+
+        Self::new(stream, meta)
+    }
+}
@@ -0,0 +1,288 @@
+use crate::inbound::Metadata;
+use crate::{Connection, Service};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use futures::ready;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::PollSemaphore;
+use tracing::warn;
+
+/// Time a client over the limit is given to receive the rejection, not to hold its connection
+/// open.
+pub(crate) const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Limit of concurrent sessions of each client, identified by its IP address.
+///
+/// Connections of unknown clients are not limited.
+#[derive(Debug)]
+pub struct ClientLimit {
+    max: AtomicUsize,
+    sessions: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ClientLimit {
+    pub fn new(max: NonZeroUsize) -> Self {
+        Self {
+            max: AtomicUsize::new(max.get()),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Changes the maximum to `max`, keeping the sessions already started.
+    pub fn resize(&self, max: NonZeroUsize) {
+        self.max.store(max.get(), Ordering::Relaxed);
+    }
+
+    /// Starts a session of the client of `meta`, held until the permit is dropped.
+    ///
+    /// Returns `None` if the client already has the maximum number of sessions.
+    pub fn acquire(self: &Arc<Self>, meta: &Metadata) -> Option<ClientPermit> {
+        // counts IPv4 clients of dual-stack listeners once, mapped to IPv6 or not
+        let Some(ip) = meta.peer_addr.map(|a| a.ip().to_canonical()) else {
+            return Some(ClientPermit {
+                limit: Arc::clone(self),
+                ip: None,
+            });
+        };
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let count = sessions.entry(ip).or_default();
+        if *count >= self.max.load(Ordering::Relaxed) {
+            warn!("too many sessions from {ip}");
+            return None;
+        }
+        *count += 1;
+
+        Some(ClientPermit {
+            limit: Arc::clone(self),
+            ip: Some(ip),
+        })
+    }
+}
+
+/// Session counted in a [`ClientLimit`].
+#[derive(Debug)]
+pub struct ClientPermit {
+    limit: Arc<ClientLimit>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        let Some(ip) = self.ip else {
+            return;
+        };
+
+        let mut sessions = self.limit.sessions.lock().unwrap();
+        if let Some(count) = sessions.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                sessions.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Limit of concurrent sessions of a listener, which connections over wait to be served.
+///
+/// It may be resized while sessions run, so that the ones started before keep counting.
+#[derive(Debug)]
+pub struct SessionLimit {
+    semaphore: Arc<Semaphore>,
+    state: Mutex<SessionState>,
+}
+
+#[derive(Debug)]
+struct SessionState {
+    max: usize,
+
+    /// Number of permits held by sessions over a lowered maximum, forgotten as they are released.
+    debt: usize,
+}
+
+impl SessionLimit {
+    pub fn new(max: NonZeroUsize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max.get())),
+            state: Mutex::new(SessionState {
+                max: max.get(),
+                debt: 0,
+            }),
+        }
+    }
+
+    /// Starts a session, waiting until the number of sessions is under the limit. The session is
+    /// held until the permit is dropped.
+    pub async fn acquire(self: &Arc<Self>) -> SessionPermit {
+        let permit = Arc::clone(&self.semaphore).acquire_owned().await;
+        SessionPermit {
+            limit: Arc::clone(self),
+            // never fails as the semaphore is never closed
+            permit: permit.ok(),
+        }
+    }
+
+    /// Returns the number of sessions that can start without waiting.
+    pub fn available_permits(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+
+    /// Changes the maximum to `max`. If it is lowered below the sessions running, new ones wait
+    /// until enough of them finish.
+    pub fn resize(&self, max: NonZeroUsize) {
+        let mut state = self.state.lock().unwrap();
+        let max = max.get();
+        if max > state.max {
+            let increase = max - state.max;
+            let paid = increase.min(state.debt);
+            state.debt -= paid;
+            self.semaphore.add_permits(increase - paid);
+        } else {
+            let excess = state.max - max;
+            state.debt += excess - self.semaphore.forget_permits(excess);
+        }
+        state.max = max;
+    }
+}
+
+/// Session counted in a [`SessionLimit`].
+#[derive(Debug)]
+pub struct SessionPermit {
+    limit: Arc<SessionLimit>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        let mut state = self.limit.state.lock().unwrap();
+        match self.permit.take() {
+            Some(permit) if state.debt > 0 => {
+                state.debt -= 1;
+                permit.forget();
+            }
+            permit => drop(permit),
+        }
+    }
+}
+
+/// Service waiting for a session of a [`SessionLimit`] to be available before it is ready.
+pub(crate) struct Limited {
+    inner: Service,
+    limit: Arc<SessionLimit>,
+    semaphore: PollSemaphore,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Limited {
+    pub fn new(inner: Service, limit: Arc<SessionLimit>) -> Self {
+        Self {
+            inner,
+            semaphore: PollSemaphore::new(Arc::clone(&limit.semaphore)),
+            limit,
+            permit: None,
+        }
+    }
+}
+
+impl Clone for Limited {
+    fn clone(&self) -> Self {
+        // the permit is only for the ready service
+        Self::new(self.inner.clone(), Arc::clone(&self.limit))
+    }
+}
+
+impl tower::Service<Connection> for Limited {
+    type Response = ();
+    type Error = anyhow::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> task::Poll<Result<(), Self::Error>> {
+        if self.permit.is_none() {
+            self.permit = ready!(self.semaphore.poll_acquire(cx));
+        }
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, conn: Connection) -> Self::Future {
+        let permit = SessionPermit {
+            limit: Arc::clone(&self.limit),
+            permit: self.permit.take(),
+        };
+        let session = self.inner.call(conn);
+        async move {
+            let _permit = permit;
+            session.await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let limit = Arc::new(ClientLimit::new(NonZeroUsize::new(2).unwrap()));
+        let meta = |addr: &str| Metadata {
+            peer_addr: Some(addr.parse().unwrap()),
+            ..Default::default()
+        };
+
+        let first = limit.acquire(&meta("192.0.2.1:1000")).unwrap();
+        let _second = limit.acquire(&meta("192.0.2.1:1001")).unwrap();
+        assert!(limit.acquire(&meta("192.0.2.1:1002")).is_none());
+        assert!(limit.acquire(&meta("192.0.2.2:1000")).is_some());
+        assert!(limit.acquire(&Metadata::default()).is_some());
+        assert!(limit.acquire(&meta("[::ffff:192.0.2.1]:1002")).is_none());
+
+        drop(first);
+        assert!(limit.acquire(&meta("192.0.2.1:1002")).is_some());
+
+        limit.resize(NonZeroUsize::new(3).unwrap());
+        let _third = limit.acquire(&meta("192.0.2.1:1003")).unwrap();
+        limit.resize(NonZeroUsize::new(1).unwrap());
+        assert!(limit.acquire(&meta("192.0.2.1:1004")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_limit() {
+        let limit = Arc::new(SessionLimit::new(NonZeroUsize::new(2).unwrap()));
+        let first = limit.acquire().await;
+        let second = limit.acquire().await;
+
+        limit.resize(NonZeroUsize::new(3).unwrap());
+        assert_eq!(limit.available_permits(), 1);
+
+        // running sessions keep counting against the lowered maximum
+        limit.resize(NonZeroUsize::new(1).unwrap());
+        assert_eq!(limit.available_permits(), 0);
+        drop(first);
+        assert_eq!(limit.available_permits(), 0);
+        drop(second);
+        assert_eq!(limit.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_session_limit_raised_back() {
+        let limit = Arc::new(SessionLimit::new(NonZeroUsize::new(4).unwrap()));
+        let sessions = vec![
+            limit.acquire().await,
+            limit.acquire().await,
+            limit.acquire().await,
+        ];
+
+        // lowering below the running sessions and raising back loses no capacity
+        limit.resize(NonZeroUsize::new(1).unwrap());
+        limit.resize(NonZeroUsize::new(5).unwrap());
+        drop(sessions);
+        assert_eq!(limit.available_permits(), 5);
+    }
+}
@@ -1,28 +1,81 @@
 mod sys;
 
 use anyhow::{Context as _, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use futures::prelude::*;
-use juno::{Dialer, Service};
-use std::collections::HashSet;
+use ipnet::IpNet;
+use juno::config::{ActiveLimits, Config, DialerConfig, Limits, ListenerConfig, SocketConfig};
+use juno::resolver::NameServer;
+use juno::server::{Binding, Listener};
+use juno::{Address, Connection, Dialer, Registry, Server, Upstream};
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::net::TcpSocket;
 use tokio::net::{lookup_host, TcpListener};
+use tokio::sync::{mpsc, oneshot};
 use tower::{Service as _, ServiceExt};
-use tracing::{debug, info, warn};
+use tracing::{error, info, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::prelude::*;
 
 #[derive(Parser)]
-#[command(version, about)]
+#[command(version, about, subcommand_negates_reqs = true)]
 struct Args {
-    /// Specifies an address to listen on for a stream.
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Specifies a configuration file defining listeners, instead of the options below.
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "listen_stream",
+            "bind_to",
+            "connect_timeout",
+            "dial_timeout",
+            "retries",
+            "retry_backoff",
+            "hosts",
+            "allow_private_destinations",
+            "deny_destination",
+            "allow_destination",
+            "rules",
+            "dns",
+            "dns_cache_size",
+            "max_client_sessions",
+            "socket_mode",
+            "socket_owner",
+            "socket_group",
+            "reuse_port",
+            "proxy_protocol",
+            "forwarded",
+            "identify_clients",
+            "provider",
+        ]
+    )]
+    config: Option<PathBuf>,
+
+    /// Specifies an address to listen on for a stream, or `unix:<path>` for a Unix domain socket.
     #[arg(short, long, value_name = "ADDRESS")]
-    #[cfg_attr(target_os = "macos", arg(required_unless_present = "launchd"))]
+    #[cfg_attr(
+        target_os = "macos",
+        arg(required_unless_present_any = ["launchd", "inetd", "config"])
+    )]
     #[cfg_attr(
         all(target_os = "linux", feature = "systemd"),
-        arg(required_unless_present = "systemd")
+        arg(required_unless_present_any = ["systemd", "inetd", "config"])
     )]
     #[cfg_attr(
         not(any(target_os = "macos", all(target_os = "linux", feature = "systemd"))),
-        arg(required = true)
+        arg(required_unless_present_any = ["inetd", "config"])
     )]
     listen_stream: Vec<String>,
 
@@ -30,26 +83,154 @@ struct Args {
     #[arg(short, long, value_name = "ADDRESS")]
     bind_to: Option<String>,
 
+    /// Specifies the timeout of each outbound connection attempt.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    connect_timeout: Option<Duration>,
+
+    /// Specifies the timeout of establishing an outbound connection, including retries.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    dial_timeout: Option<Duration>,
+
+    /// Specifies the number of retries of failed outbound connections.
+    #[arg(long, value_name = "COUNT", default_value_t = 0)]
+    retries: u32,
+
+    /// Specifies the delay before the first retry, doubled on each subsequent retry.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration,
+        default_value = "100ms"
+    )]
+    retry_backoff: Duration,
+
+    /// Specifies a file of static overrides of outbound destinations.
+    #[arg(long, value_name = "FILE")]
+    hosts: Option<PathBuf>,
+
+    /// Allows outbound connections to private, loopback and other special-purpose addresses,
+    /// which are denied by default.
+    #[arg(long)]
+    allow_private_destinations: bool,
+
+    /// Specifies an additional range of addresses outbound connections are denied to.
+    #[arg(long, value_name = "CIDR")]
+    deny_destination: Vec<IpNet>,
+
+    /// Specifies a range of addresses outbound connections are allowed to regardless of denials.
+    #[arg(long, value_name = "CIDR")]
+    allow_destination: Vec<IpNet>,
+
+    /// Specifies a file of outbound routing rules.
+    #[arg(long, value_name = "FILE")]
+    rules: Option<PathBuf>,
+
+    /// Specifies a DNS server to resolve names of outbound connections, instead of the system resolver.
+    #[arg(long, value_name = "SERVER")]
+    dns: Vec<NameServer>,
+
+    /// Specifies the maximum number of DNS records to cache.
+    #[arg(long, value_name = "COUNT", default_value_t = 1024)]
+    dns_cache_size: usize,
+
     /// Specifies the name of the socket entry in the service's Sockets dictionary.
     #[cfg(target_os = "macos")]
-    #[arg(long, value_name = "NAME", conflicts_with = "listen_stream")]
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["listen_stream", "inetd", "config"])]
     launchd: Option<String>,
 
     /// Runs in systemd socket activation mode.
     #[cfg(all(target_os = "linux", feature = "systemd"))]
-    #[arg(long, value_name = "NAME", conflicts_with = "listen_stream")]
+    #[arg(long, conflicts_with_all = ["listen_stream", "inetd", "config"])]
     systemd: bool,
 
+    /// Serves a single connection passed by inetd, or by systemd with `Accept=yes`, and exits.
+    #[arg(long, conflicts_with_all = ["listen_stream", "config"])]
+    inetd: bool,
+
     /// Specifies the name of the service provider.
-    #[arg(short, long, value_name = "NAME", required = true)]
-    provider: String,
+    #[arg(short, long, value_name = "NAME", required_unless_present = "config")]
+    provider: Option<String>,
+
+    /// Specifies the permissions of Unix domain sockets in octal.
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    socket_mode: Option<u32>,
+
+    /// Specifies the user owning Unix domain sockets.
+    #[arg(long, value_name = "USER")]
+    socket_owner: Option<String>,
+
+    /// Specifies the group owning Unix domain sockets.
+    #[arg(long, value_name = "GROUP")]
+    socket_group: Option<String>,
+
+    /// Specifies the number of TCP sockets to listen on each address with, sharing it by SO_REUSEPORT.
+    #[arg(long, value_name = "COUNT")]
+    reuse_port: Option<NonZeroUsize>,
+
+    /// Specifies a range of addresses of proxies in front, whose connections start with a PROXY protocol header.
+    #[arg(long, value_name = "CIDR")]
+    proxy_protocol: Vec<IpNet>,
+
+    /// Adds the addresses of clients to HTTP requests in `Forwarded` headers.
+    #[arg(long)]
+    forwarded: bool,
+
+    /// Identifies the local processes of clients connected over TCP loopback for logs, on Linux.
+    #[arg(long)]
+    identify_clients: bool,
+
+    /// Specifies the maximum number of concurrent sessions, over which connections wait to be accepted.
+    #[arg(long, value_name = "COUNT")]
+    max_sessions: Option<NonZeroUsize>,
+
+    /// Specifies the maximum number of concurrent sessions of each client, over which connections are rejected.
+    #[arg(long, value_name = "COUNT")]
+    max_client_sessions: Option<NonZeroUsize>,
+
+    /// Specifies how long to wait for sessions to finish on shutdown before closing them.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration,
+        default_value = "30s"
+    )]
+    drain_timeout: Duration,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Connects to a destination through a proxy server, relaying stdin and stdout.
+    Connect {
+        /// Specifies the proxy server, written as `http://`, `socks4a://` or `socks5://<host>:<port>`.
+        #[arg(long, value_name = "PROXY")]
+        via: Upstream,
+
+        /// Specifies the user to authenticate as, with the password in the `JUNO_PASSWORD` environment variable.
+        #[arg(short, long, value_name = "USER")]
+        user: Option<String>,
+
+        /// Specifies the destination.
+        #[arg(value_name = "HOST:PORT")]
+        destination: Address,
+    },
+}
+
+/// Environment variable holding the password `connect` authenticates with.
+const PASSWORD_ENV: &str = "JUNO_PASSWORD";
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
+    // `connect` relays stdout, and inetd passes the connection as stdout and often as stderr too
+    let writer = match (&args.command, args.inetd, sys::stderr_is_socket()) {
+        (Some(Command::Connect { .. }), ..) | (None, true, false) => {
+            BoxMakeWriter::new(std::io::stderr)
+        }
+        (None, true, true) => BoxMakeWriter::new(std::io::sink),
+        (None, false, _) => BoxMakeWriter::new(std::io::stdout),
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(writer))
         .with(
             tracing_subscriber::EnvFilter::builder()
                 .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
@@ -64,30 +245,449 @@ fn main() -> Result<()> {
 }
 
 async fn async_main(args: Args) -> Result<()> {
-    let dialer = if let Some(a) = &args.bind_to {
-        Dialer::bind(a).await?
-    } else {
-        Dialer::default()
+    if let Some(Command::Connect {
+        via,
+        user,
+        destination,
+    }) = &args.command
+    {
+        return connect(via, user.as_deref(), destination).await;
+    }
+
+    let registry = Registry::default();
+    if args.inetd {
+        return serve_once(&args, &registry).await;
+    }
+
+    let inherited = sys::inherited_listeners()?;
+    let upgraded = inherited.is_some();
+    let (bindings, sockets, limits) = {
+        let sockets = match (inherited, &args.config) {
+            (Some(sockets), _) => sockets,
+            (None, Some(_)) => activated_sockets()?,
+            (None, None) => {
+                let mut sockets = Sockets::new();
+                for s in bind_all(&args).await? {
+                    sockets.entry(s.local_addr()?).or_default().push(s);
+                }
+                sockets
+            }
+        };
+        load(&args, &registry, &sockets, &ListenerLimits::new()).await?
     };
 
-    let service = juno::create_service(&args.provider, dialer)?;
+    let drain_timeout = args.drain_timeout;
+    let max_sessions = args.max_sessions;
+    let (tx, mut rx) = mpsc::channel(1);
+    let hangup = sys::reload_signal()?;
+    let upgrade = sys::upgrade_signal()?;
+    let (upgraded_tx, upgraded_rx) = oneshot::channel();
+    let controller = Controller {
+        args,
+        registry,
+        sockets,
+        limits,
+    };
+    tokio::spawn(control(controller, hangup, upgrade, tx, upgraded_tx));
 
-    let listeners = bind_all(&args)
-        .await?
-        .into_iter()
-        .map(|l| listen(l, service.clone()));
+    // the first signal shuts down gracefully, and the second closes the remaining sessions
+    let mut signals = Box::pin(sys::shutdown_signal()?);
+    let (signals_tx, signals_rx) = oneshot::channel();
+    let shutdown = async move {
+        tokio::select! {
+            _ = signals.next() => {
+                info!("shutting down");
+                notify(&[("STOPPING", "1")]);
+            }
+            // leaves the service running in the new process
+            Ok(()) = upgraded_rx => info!("upgraded, shutting down"),
+        }
+        let _ = signals_tx.send(signals);
+    };
+    let force_shutdown = async move {
+        if let Ok(mut signals) = signals_rx.await {
+            signals.next().await;
+        }
+    };
+
+    let mut server = Server::builder()
+        .shutdown(shutdown)
+        .force_shutdown(force_shutdown)
+        .drain_timeout(drain_timeout)
+        .max_sessions(max_sessions)
+        .reload(stream::poll_fn(move |cx| rx.poll_recv(cx)));
+    for (listener, service) in bindings {
+        server = server.listen_service(listener, service);
+    }
+
+    let server = server.build()?;
+    tokio::spawn(report_exhaustions(server.metrics()));
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    tokio::spawn(supervise(server.metrics()));
+    // the new process takes over the service on upgrade, with `NotifyAccess=all`
+    let pid = std::process::id().to_string();
+    match upgraded {
+        true => notify(&[("READY", "1"), ("MAINPID", &pid)]),
+        false => notify(&[("READY", "1")]),
+    }
+    if let Err(e) = sys::report_ready() {
+        error!("{e:#}");
+    }
+    server.run().await
+}
+
+/// Notifies systemd of the state of the server, if it supervises juno.
+fn notify(state: &[(&str, &str)]) {
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    if let Err(e) = sys::notify(state) {
+        warn!("{e:#}");
+    }
+    #[cfg(not(all(target_os = "linux", feature = "systemd")))]
+    let _ = state;
+}
+
+/// Logs periodically how many times accepting connections failed for exhausted resources, like
+/// the limit of open files, if it did since the last report.
+async fn report_exhaustions(metrics: Arc<juno::server::Metrics>) {
+    let mut interval = tokio::time::interval(EXHAUSTION_INTERVAL);
+    let mut last = 0;
+    loop {
+        interval.tick().await;
+        let count = metrics.accept_exhaustions();
+        if count > last {
+            let n = count - last;
+            warn!("{n} accept errors for exhausted resources, {count} in total");
+        }
+        last = count;
+    }
+}
+
+/// Interval of reporting the accept errors caused by exhausted resources.
+const EXHAUSTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Reports the number of sessions to systemd periodically, pinging its watchdog if enabled.
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+async fn supervise(metrics: Arc<juno::server::Metrics>) {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+
+    let watchdog = sys::watchdog_timeout().unwrap_or_else(|e| {
+        warn!("{e:#}");
+        None
+    });
+    // pings twice as often as the watchdog expires, as sd_watchdog_enabled(3) recommends
+    let period = watchdog.map_or(STATUS_INTERVAL, |t| (t / 2).min(STATUS_INTERVAL));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let status = format!(
+            "sessions: {}, accepted: {}, accept exhaustions: {}",
+            metrics.sessions(),
+            metrics.accepted(),
+            metrics.accept_exhaustions()
+        );
+        let mut state = vec![("STATUS", status.as_str())];
+        if watchdog.is_some() {
+            state.push(("WATCHDOG", "1"));
+        }
+        notify(&state);
+    }
+}
+
+/// Interval of reporting the status to systemd.
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Serves the single connection passed by inetd or systemd, or stdin and stdout if none is.
+async fn serve_once(args: &Args, registry: &Registry) -> Result<()> {
+    let config = cli_config(args)?;
+    let listener = &config.listeners[0];
+    let mut service = listener
+        .service(registry, &config.upstreams, &listener.limits.activate(None))
+        .await?;
+    let conn = match sys::inherited_connection()? {
+        Some(conn) => conn,
+        None => {
+            let stdio = tokio::io::join(tokio::io::stdin(), tokio::io::stdout());
+            Connection::new(stdio, Default::default()).boxed()
+        }
+    };
+
+    service.ready().await?.call(conn).await
+}
+
+/// Changes the running server on signals.
+trait Control {
+    /// Reloads the configuration, returning the listeners to serve if loaded.
+    async fn reload(&mut self) -> Option<Vec<Binding>>;
 
-    tokio::select! {
-        r = future::try_join_all(listeners) => {
-            r?;
-        },
-        r = sys::recv_signal() => r?,
+    /// Upgrades the executable, returning whether the new process took over.
+    async fn upgrade(&mut self) -> bool;
+}
+
+/// Handles the `hangup` and `upgrade` signals with `control`, sending the reloaded listeners to
+/// `tx`, until upgraded or the server shuts down.
+async fn control(
+    mut control: impl Control,
+    hangup: impl Stream<Item = ()>,
+    upgrade: impl Stream<Item = ()>,
+    tx: mpsc::Sender<Vec<Binding>>,
+    upgraded_tx: oneshot::Sender<()>,
+) {
+    let mut hangup = pin!(hangup);
+    let mut upgrade = pin!(upgrade);
+    loop {
+        tokio::select! {
+            Some(()) = hangup.next() => {
+                if let Some(bindings) = control.reload().await {
+                    let _ = tx.send(bindings).await;
+                }
+            }
+            Some(()) = upgrade.next() => {
+                if control.upgrade().await {
+                    let _ = upgraded_tx.send(());
+                    // leaves further signals to the new process, ignoring them while draining
+                    break;
+                }
+            }
+            // closes the sockets as soon as the server shuts down
+            _ = tx.closed() => break,
+        }
     }
+}
+
+/// State of the server changed by reloads and needed to upgrade.
+struct Controller {
+    args: Args,
+    registry: Registry,
+    sockets: Sockets,
+    limits: ListenerLimits,
+}
+
+impl Control for Controller {
+    async fn reload(&mut self) -> Option<Vec<Binding>> {
+        info!("reloading configuration");
+        match load(&self.args, &self.registry, &self.sockets, &self.limits).await {
+            Ok((bindings, sockets, limits)) => {
+                self.sockets = sockets;
+                self.limits = limits;
+                Some(bindings)
+            }
+            Err(e) => {
+                error!("failed to reload configuration: {e:#}");
+                None
+            }
+        }
+    }
+
+    async fn upgrade(&mut self) -> bool {
+        info!("upgrading");
+        match sys::upgrade(&self.sockets).await {
+            Ok(pid) => {
+                // hands the service over before exiting, in case the new process cannot tell
+                // systemd itself
+                notify(&[("MAINPID", &pid.to_string())]);
+                true
+            }
+            Err(e) => {
+                error!("failed to upgrade: {e:#}");
+                false
+            }
+        }
+    }
+}
+
+/// Sockets bound to each address listeners listen on.
+type Sockets = HashMap<String, Vec<Arc<dyn Listener>>>;
+
+/// Limits of the listeners in effect, keyed by each address they listen on.
+type ListenerLimits = HashMap<String, ActiveLimits>;
+
+/// Loads the configuration and binds its listeners, reusing `sockets` bound to the same addresses
+/// and the `limits` of the listeners listening on any of them.
+async fn load(
+    args: &Args,
+    registry: &Registry,
+    sockets: &Sockets,
+    limits: &ListenerLimits,
+) -> Result<(Vec<Binding>, Sockets, ListenerLimits)> {
+    let Some(path) = &args.config else {
+        // listeners defined by the options never change
+        let config = cli_config(args)?;
+        let listener = &config.listeners[0];
+        let active = listener.limits.activate(limits.values().next());
+        let service = listener
+            .service(registry, &config.upstreams, &active)
+            .await?;
+        active.resize(&listener.limits);
+        let bindings = sockets
+            .values()
+            .flatten()
+            .map(|s| (s.clone(), service.clone()))
+            .collect();
+        let limits = sockets
+            .keys()
+            .map(|addr| (addr.clone(), active.clone()))
+            .collect();
+        return Ok((bindings, sockets.clone(), limits));
+    };
+
+    let config = Config::load(path)?;
+    let mut bound = Sockets::new();
+    let mut bindings = vec![];
+    let mut activated = vec![];
+    let mut updates = vec![];
+    for listener in &config.listeners {
+        let current = listener.listen.iter().find_map(|addr| limits.get(addr));
+        let active = listener.limits.activate(current);
+        let service = listener
+            .service(registry, &config.upstreams, &active)
+            .await?;
+        activated.push((active, listener));
+        for addr in &listener.listen {
+            let sockets = match sockets.get(addr) {
+                Some(sockets) => {
+                    match addr.strip_prefix("unix:") {
+                        Some(path) => updates.push((path, &listener.socket)),
+                        None => check_reuse_port(addr, sockets, &listener.socket),
+                    }
+                    sockets.clone()
+                }
+                None => bind(addr, &listener.socket).await?,
+            };
+            bindings.extend(sockets.iter().map(|s| (s.clone(), service.clone())));
+            bound.insert(addr.clone(), sockets);
+        }
+    }
+
+    // keeps activated sockets open for later configurations, as they cannot be activated again
+    for (addr, sockets) in sockets {
+        if addr.starts_with("systemd:") && !bound.contains_key(addr) {
+            bound.insert(addr.clone(), sockets.clone());
+        }
+    }
+
+    // changes the sockets and limits only once the whole configuration is valid, not to change
+    // them otherwise
+    for (path, socket) in updates {
+        sys::update_unix(path, socket)?;
+    }
+    let mut limits = ListenerLimits::new();
+    for (active, listener) in activated {
+        active.resize(&listener.limits);
+        for addr in &listener.listen {
+            limits.insert(addr.clone(), active.clone());
+        }
+    }
+
+    Ok((bindings, bound, limits))
+}
+
+/// Warns if the number of `sockets` kept bound to `addr` differs from the one `socket` configures,
+/// as sockets are only bound again on restart.
+fn check_reuse_port(addr: &str, sockets: &[Arc<dyn Listener>], socket: &SocketConfig) {
+    if addr.starts_with("systemd:") {
+        return;
+    }
+
+    let addrs = sockets
+        .iter()
+        .filter_map(|s| s.local_addr().ok())
+        .collect::<HashSet<_>>();
+    let count = socket.reuse_port.map_or(1, NonZeroUsize::get);
+    if sockets.len() != addrs.len() * count {
+        warn!(
+            "keeping {} sockets bound to {addr}, as reuse_port only changes on restart",
+            sockets.len()
+        );
+    }
+}
+
+/// Creates the configuration of the single listener defined by the options.
+fn cli_config(args: &Args) -> Result<Config> {
+    let dialer = DialerConfig {
+        bind_to: args.bind_to.clone(),
+        connect_timeout: args.connect_timeout,
+        dial_timeout: args.dial_timeout,
+        retries: args.retries,
+        retry_backoff: args.retry_backoff,
+        hosts: args.hosts.clone(),
+        allow_private_destinations: args.allow_private_destinations,
+        deny_destinations: args.deny_destination.clone(),
+        allow_destinations: args.allow_destination.clone(),
+        dns: args.dns.clone(),
+        dns_cache_size: args.dns_cache_size,
+    };
+
+    let provider = args
+        .provider
+        .clone()
+        .context("the provider is not specified")?;
+    Ok(Config {
+        upstreams: Default::default(),
+        listeners: vec![ListenerConfig {
+            listen: args.listen_stream.clone(),
+            provider,
+            auth: None,
+            rules: args.rules.clone(),
+            dialer,
+            limits: Limits {
+                max_sessions: None,
+                max_sessions_per_client: args.max_client_sessions,
+            },
+            socket: socket_config(args),
+            proxy_protocol: args.proxy_protocol.clone(),
+            forwarded: args.forwarded,
+            identify_clients: args.identify_clients,
+        }],
+    })
+}
+
+fn socket_config(args: &Args) -> SocketConfig {
+    SocketConfig {
+        mode: args.socket_mode,
+        owner: args.socket_owner.clone(),
+        group: args.socket_group.clone(),
+        reuse_port: args.reuse_port,
+    }
+}
+
+fn parse_mode(s: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(s, 8)
+}
+
+async fn connect(proxy: &Upstream, user: Option<&str>, addr: &Address) -> Result<()> {
+    // takes the password from the environment not to expose it in the arguments
+    let credentials = user
+        .map(|user| {
+            let password = std::env::var(PASSWORD_ENV)
+                .with_context(|| format!("{PASSWORD_ENV} is not set for user `{user}`"))?;
+            Ok::<_, anyhow::Error>(juno::client::Credentials {
+                username: user.to_string(),
+                password,
+            })
+        })
+        .transpose()?;
+
+    let mut stream = Dialer::default()
+        .dial(proxy.addr())
+        .await
+        .with_context(|| format!("failed to connect to {}", proxy.addr()))?;
+
+    let reply = juno::client::connect(&mut stream, proxy, addr, credentials.as_ref()).await?;
+    eprintln!("{reply}");
+    if !reply.is_success() {
+        anyhow::bail!("failed to connect to {addr}");
+    }
+
+    let mut stdio = tokio::io::join(tokio::io::stdin(), tokio::io::stdout());
+    tokio::io::copy_bidirectional(&mut stdio, &mut stream).await?;
 
     Ok(())
 }
 
-async fn bind_all(args: &Args) -> Result<Vec<TcpListener>> {
+async fn bind_all(args: &Args) -> Result<Vec<Arc<dyn Listener>>> {
     #[cfg(target_os = "macos")]
     if let Some(name) = &args.launchd {
         return sys::activate_socket(name);
@@ -98,45 +698,90 @@ async fn bind_all(args: &Args) -> Result<Vec<TcpListener>> {
         return sys::activate_socket();
     }
 
+    let socket = socket_config(args);
     stream::iter(args.listen_stream.iter().collect::<HashSet<_>>())
-        .then(|addr| {
-            lookup_host(addr).map(move |r| r.with_context(|| format!("failed to resolve {addr}")))
-        })
-        .map_ok(|addrs| {
-            stream::iter(addrs).then(|addr| {
-                TcpListener::bind(addr)
-                    .map(move |r| r.with_context(|| format!("failed to bind to {addr}")))
-            })
-        })
+        .then(|addr| bind(addr, &socket))
+        .map_ok(|listeners| stream::iter(listeners).map(Ok))
         .try_flatten()
         .try_collect()
         .await
 }
 
-async fn listen(listener: TcpListener, mut service: Service) -> Result<()> {
-    match listener.local_addr() {
-        Ok(addr) => {
-            info!("listening on {addr}");
-        }
-        Err(e) => {
-            warn!("failed to get local address: {e}")
-        }
+/// Takes the sockets passed by systemd, keyed by `systemd:<name>` for listeners to listen on.
+fn activated_sockets() -> Result<Sockets> {
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    let sockets = sys::activate_named_sockets()?
+        .into_iter()
+        .map(|(name, sockets)| (format!("systemd:{name}"), sockets))
+        .collect();
+    #[cfg(not(all(target_os = "linux", feature = "systemd")))]
+    let sockets = Sockets::new();
+    Ok(sockets)
+}
+
+async fn bind(addr: &String, socket: &SocketConfig) -> Result<Vec<Arc<dyn Listener>>> {
+    if let Some(name) = addr.strip_prefix("systemd:") {
+        return Err(anyhow::anyhow!(
+            "no socket named `{name}` is passed by systemd"
+        ));
+    }
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return Ok(vec![sys::bind_unix(path, socket)?]);
     }
 
-    loop {
-        let (client, addr) = listener
-            .accept()
-            .map(|r| r.context("failed to accept connection"))
-            .await?;
-        debug!("connected from {addr}");
-        tokio::task::spawn(service.ready().await?.call(client));
+    let addrs = lookup_host(addr)
+        .await
+        .with_context(|| format!("failed to resolve {addr}"))?;
+
+    if let Some(count) = socket.reuse_port {
+        return addrs
+            .flat_map(|addr| iter::repeat_n(addr, count.get()))
+            .map(|addr| {
+                let listener =
+                    bind_reuse_port(addr).with_context(|| format!("failed to bind to {addr}"))?;
+                Ok(Arc::new(listener) as Arc<dyn Listener>)
+            })
+            .collect();
     }
+
+    stream::iter(addrs)
+        .then(|addr| {
+            TcpListener::bind(addr).map(move |r| {
+                let listener = r.with_context(|| format!("failed to bind to {addr}"))?;
+                Ok(Arc::new(listener) as Arc<dyn Listener>)
+            })
+        })
+        .try_collect()
+        .await
+}
+
+/// Binds to `addr` with `SO_REUSEPORT`, letting the kernel spread connections across the
+/// listeners bound to the same address.
+#[cfg(unix)]
+fn bind_reuse_port(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+#[cfg(not(unix))]
+fn bind_reuse_port(_: SocketAddr) -> std::io::Result<TcpListener> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported",
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     #[allow(unused_imports)]
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     #[cfg(target_os = "macos")]
     #[test]
@@ -165,4 +810,121 @@ mod tests {
         );
         assert!(Args::try_parse_from(["", "-p", "provider"]).is_err());
     }
+
+    #[test]
+    fn test_inetd() {
+        assert!(Args::try_parse_from(["", "-p", "provider", "--inetd"]).is_ok());
+        assert!(
+            Args::try_parse_from(["", "-p", "provider", "-l", "host:port", "--inetd"]).is_err()
+        );
+        assert!(Args::try_parse_from(["", "-c", "juno.toml", "--inetd"]).is_err());
+    }
+
+    #[test]
+    fn test_config() {
+        assert!(Args::try_parse_from(["", "-c", "juno.toml"]).is_ok());
+        assert!(Args::try_parse_from(["", "-c", "juno.toml", "-p", "http"]).is_err());
+        assert!(Args::try_parse_from(["", "-c", "juno.toml", "--retries", "3"]).is_err());
+        assert!(Args::try_parse_from(["", "-c", "juno.toml", "--socket-mode", "660"]).is_err());
+        assert!(Args::try_parse_from(["", "-c", "juno.toml", "--forwarded"]).is_err());
+    }
+
+    #[test]
+    fn test_reuse_port() {
+        let args = ["", "-p", "http", "-l", "host:port", "--reuse-port"];
+        assert!(Args::try_parse_from(args.iter().chain(&["4"])).is_ok());
+        assert!(Args::try_parse_from(args.iter().chain(&["0"])).is_err());
+    }
+
+    #[test]
+    fn test_max_sessions() {
+        let args = ["", "-p", "http", "-l", "host:port", "--max-sessions"];
+        assert!(Args::try_parse_from(args.iter().chain(&["100"])).is_ok());
+        assert!(Args::try_parse_from(args.iter().chain(&["0"])).is_err());
+
+        let args = ["", "-p", "http", "-l", "host:port", "--max-client-sessions"];
+        assert!(Args::try_parse_from(args.iter().chain(&["16"])).is_ok());
+        assert!(Args::try_parse_from(args.iter().chain(&["0"])).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_reuse_port() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let socket = SocketConfig {
+            reuse_port: NonZeroUsize::new(2),
+            ..Default::default()
+        };
+        let listeners = bind(&format!("127.0.0.1:{port}"), &socket).await.unwrap();
+        assert_eq!(listeners.len(), 2);
+        for listener in listeners {
+            assert_eq!(listener.local_addr().unwrap(), format!("127.0.0.1:{port}"));
+        }
+    }
+
+    #[test]
+    fn test_connect() {
+        assert!(Args::try_parse_from([
+            "",
+            "connect",
+            "--via",
+            "socks5://127.0.0.1:1080",
+            "example.com:80"
+        ])
+        .is_ok());
+        assert!(Args::try_parse_from([
+            "",
+            "connect",
+            "--via",
+            "http://127.0.0.1:8080",
+            "--user",
+            "alice",
+            "example.com:80"
+        ])
+        .is_ok());
+        assert!(Args::try_parse_from(["", "connect", "example.com:80"]).is_err());
+        assert!(
+            Args::try_parse_from(["", "connect", "--via", "127.0.0.1:1080", "example.com:80"])
+                .is_err()
+        );
+    }
+
+    /// Counts the upgrades, failing as many as `failures` first.
+    struct Upgrades {
+        failures: usize,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl Control for Upgrades {
+        async fn reload(&mut self) -> Option<Vec<Binding>> {
+            None
+        }
+
+        async fn upgrade(&mut self) -> bool {
+            self.count.fetch_add(1, Ordering::Relaxed) >= self.failures
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_control_upgrade() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let upgrades = Upgrades {
+            failures: 1,
+            count: count.clone(),
+        };
+        let (tx, _rx) = mpsc::channel(1);
+        let (upgraded_tx, upgraded_rx) = oneshot::channel();
+        let signals = stream::iter([(); 3]);
+        let control = control(upgrades, stream::pending(), signals, tx, upgraded_tx);
+        tokio::time::timeout(Duration::from_secs(1), control)
+            .await
+            .unwrap();
+        assert!(upgraded_rx.await.is_ok());
+        // the third signal is left to the new process
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
 }
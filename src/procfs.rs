@@ -0,0 +1,224 @@
+//! Lookup of local processes through the proc filesystem of Linux.
+
+use crate::inbound::PeerCred;
+use crate::{Connection, Service};
+use anyhow::Error;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use ipnet::IpNet;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::task;
+use tracing::debug;
+
+/// Service identifying the local processes of clients connected over TCP loopback before passing
+/// their connections to the inner service.
+///
+/// Each identification scans the open files of all processes, so it is only worth it for
+/// listeners whose rules or logs need the clients' credentials. Connections from `proxies`, which
+/// tell the addresses of clients in PROXY protocol headers, are not identified, as their processes
+/// are the proxies.
+#[derive(Clone)]
+pub struct Identify {
+    inner: Service,
+    proxies: Arc<Vec<IpNet>>,
+}
+
+impl Identify {
+    pub fn new(inner: Service, proxies: Vec<IpNet>) -> Self {
+        Self {
+            inner,
+            proxies: Arc::new(proxies),
+        }
+    }
+}
+
+impl tower::Service<Connection> for Identify {
+    type Response = ();
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut conn: Connection) -> Self::Future {
+        let (Some(peer), Some(local)) = (conn.meta.peer_addr, conn.meta.local_addr) else {
+            return self.inner.call(conn).boxed();
+        };
+        let ip = peer.ip().to_canonical();
+        if !ip.is_loopback() || self.proxies.iter().any(|n| n.contains(&ip)) {
+            return self.inner.call(conn).boxed();
+        }
+
+        // takes the ready service to call it after identifying the client
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        async move {
+            // scanning the proc filesystem blocks
+            let owner = tokio::task::spawn_blocking(move || tcp_owner(peer, local));
+            conn.meta.peer_cred = owner.await.ok().flatten();
+            if let Some(cred) = &conn.meta.peer_cred {
+                debug!("identified {peer} as {cred}");
+            }
+            inner.call(conn).await
+        }
+        .boxed()
+    }
+}
+
+/// Looks up the local process owning the TCP socket connected from `peer` to `local`.
+///
+/// The process may be unknown while its user is, if the process is not visible to this one.
+pub fn tcp_owner(peer: SocketAddr, local: SocketAddr) -> Option<PeerCred> {
+    let (peer, local) = (canonical(peer), canonical(local));
+    let (uid, inode) = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .into_iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .find_map(|table| find_socket(&table, peer, local))?;
+
+    let pid = find_process(inode);
+    Some(PeerCred {
+        uid,
+        gid: pid.and_then(group),
+        pid,
+        program: pid.and_then(program),
+    })
+}
+
+/// Returns the name of the program of the process `pid`.
+pub fn program(pid: i32) -> Option<String> {
+    let comm = fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+    Some(comm.trim_end().to_string())
+}
+
+/// Returns the effective group ID of the process `pid`.
+fn group(pid: i32) -> Option<u32> {
+    let status = fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("Gid:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+/// Finds the socket with the addresses in a table of `/proc/net/tcp`, returning its owner and
+/// inode.
+fn find_socket(table: &str, local: SocketAddr, remote: SocketAddr) -> Option<(u32, u64)> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() < 10 || parse_addr(fields[1])? != local || parse_addr(fields[2])? != remote
+        {
+            return None;
+        }
+        Some((fields[7].parse().ok()?, fields[9].parse().ok()?))
+    })
+}
+
+/// Finds the process holding the socket `inode` open.
+fn find_process(inode: u64) -> Option<i32> {
+    let link = format!("socket:[{inode}]");
+    fs::read_dir("/proc").ok()?.flatten().find_map(|entry| {
+        let pid = entry.file_name().to_str()?.parse().ok()?;
+        let mut fds = fs::read_dir(entry.path().join("fd")).ok()?;
+        fds.any(|fd| {
+            fd.is_ok_and(|fd| fs::read_link(fd.path()).is_ok_and(|l| l.as_os_str() == &*link))
+        })
+        .then_some(pid)
+    })
+}
+
+/// Parses an address of `/proc/net/tcp`, of which the IP address is printed in hexadecimal words
+/// of the native byte order.
+fn parse_addr(s: &str) -> Option<SocketAddr> {
+    let (ip, port) = s.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let words = (0..ip.len())
+        .step_by(8)
+        .map(|i| u32::from_str_radix(ip.get(i..i + 8)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    let ip = match words[..] {
+        [word] => IpAddr::V4(Ipv4Addr::from(word.to_ne_bytes())),
+        [a, b, c, d] => {
+            let mut octets = [0; 16];
+            for (chunk, word) in octets.chunks_mut(4).zip([a, b, c, d]) {
+                chunk.copy_from_slice(&word.to_ne_bytes());
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+
+    Some(canonical(SocketAddr::new(ip, port)))
+}
+
+/// Converts an IPv4-mapped IPv6 address into IPv4, as both name the same socket.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn test_find_socket() {
+        let table = "\
+  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1000 1
+   1: 0100007F:D431 0100007F:1F90 01 00000000:00000000 00:00000000 00000000  1001        0 2000 1
+";
+        let peer = "127.0.0.1:54321".parse().unwrap();
+        let local = canonical("[::ffff:127.0.0.1]:8080".parse().unwrap());
+        assert_eq!(find_socket(table, peer, local), Some((1001, 2000)));
+        assert_eq!(find_socket(table, local, peer), None);
+
+        assert_eq!(
+            parse_addr("00000000000000000000000001000000:0050"),
+            Some("[::1]:80".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tcp_owner() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let client = tokio::net::TcpStream::connect(local).await.unwrap();
+
+        let cred = tcp_owner(client.local_addr().unwrap(), local).unwrap();
+        assert_eq!(cred.pid, Some(std::process::id() as i32));
+        assert!(cred.program.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_identify() {
+        use tower::{Service as _, ServiceExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let identified = |proxies: Vec<IpNet>| async {
+            let _client = tokio::net::TcpStream::connect(local).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let inner = Service::new(tower::service_fn(move |conn: Connection| {
+                let _ = tx.send(conn.meta.peer_cred);
+                async { Ok(()) }
+            }));
+            let mut identify = Identify::new(inner, proxies);
+            let conn = Connection::from(stream).boxed();
+            identify.ready().await.unwrap().call(conn).await.unwrap();
+            rx.recv().await.unwrap()
+        };
+
+        let cred = identified(vec![]).await.unwrap();
+        assert_eq!(cred.pid, Some(std::process::id() as i32));
+        assert!(identified(vec!["127.0.0.0/8".parse().unwrap()])
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,409 @@
+//! PROXY protocol of HAProxy, conveying the addresses of clients through proxies in front.
+
+use crate::{Connection, Service};
+use anyhow::Error;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::task;
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+use tracing::{debug, warn};
+
+/// Service reading a PROXY protocol header, version 1 or 2, from connections of trusted proxies
+/// before passing them to the inner service with the addresses in the header.
+///
+/// Connections from other sources are passed as they are.
+#[derive(Clone)]
+pub struct ProxyProtocol {
+    inner: Service,
+    trusted: Arc<Vec<IpNet>>,
+}
+
+impl ProxyProtocol {
+    pub fn new(inner: Service, trusted: Vec<IpNet>) -> Self {
+        Self {
+            inner,
+            trusted: Arc::new(trusted),
+        }
+    }
+
+    fn is_trusted(&self, addr: Option<SocketAddr>) -> bool {
+        matches!(addr, Some(a) if self.trusted.iter().any(|n| n.contains(&a.ip().to_canonical())))
+    }
+}
+
+impl tower::Service<Connection> for ProxyProtocol {
+    type Response = ();
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, conn: Connection) -> Self::Future {
+        if !self.is_trusted(conn.meta.peer_addr) {
+            return self.inner.call(conn).boxed();
+        }
+
+        // takes the ready service to call it after reading the header
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        async move {
+            match proxied(conn).await {
+                Some(conn) => inner.call(conn).await,
+                None => Ok(()),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Reads the header from the connection of a proxy, replacing the addresses of the connection with
+/// the ones in it. Returns `None` if the header is invalid.
+async fn proxied(mut conn: Connection) -> Option<Connection> {
+    let proxy = conn.meta.peer_addr?;
+    let header = tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut conn.io))
+        .await
+        .map_err(io::Error::from)
+        .and_then(|r| r);
+    match header {
+        Ok(Some((peer, local))) => {
+            debug!("connected from {peer} through {proxy}");
+            conn.meta.peer_addr = Some(peer);
+            conn.meta.local_addr = Some(local);
+            // identifies the proxy rather than the client
+            conn.meta.peer_cred = None;
+        }
+        Ok(None) => {}
+        Err(e) => {
+            warn!("failed to read PROXY protocol header from {proxy}: {e}");
+            return None;
+        }
+    }
+
+    Some(conn)
+}
+
+/// Version of the PROXY protocol, either `1` of text or `2` of binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "u8")]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl TryFrom<u8> for Version {
+    type Error = String;
+
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(Self::V1),
+            2 => Ok(Self::V2),
+            _ => Err(format!("unsupported PROXY protocol version {version}")),
+        }
+    }
+}
+
+/// Encodes a header of a connection from `src` to `dst`, telling no addresses if `src` is
+/// unknown.
+pub fn encode(version: Version, src: Option<SocketAddr>, dst: SocketAddr) -> Vec<u8> {
+    // both addresses must be of the same family, mapping IPv4 into IPv6 if they are not
+    let addrs = src.map(
+        |src| match (src.ip().to_canonical(), dst.ip().to_canonical()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+            (s, d) => (IpAddr::V6(to_ipv6(s)), IpAddr::V6(to_ipv6(d))),
+        },
+    );
+    let ports = (src.map_or(0, |a| a.port()), dst.port());
+
+    match (version, addrs) {
+        (Version::V1, Some((s, d))) => {
+            let family = if s.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!("PROXY {family} {s} {d} {} {}\r\n", ports.0, ports.1).into_bytes()
+        }
+        (Version::V1, None) => b"PROXY UNKNOWN\r\n".to_vec(),
+        (Version::V2, addrs) => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let (command, family, body) = match addrs {
+                Some((IpAddr::V4(s), IpAddr::V4(d))) => {
+                    (0x21, 0x11, [&s.octets()[..], &d.octets()].concat())
+                }
+                Some((s, d)) => (
+                    0x21,
+                    0x21,
+                    [to_ipv6(s).octets(), to_ipv6(d).octets()].concat(),
+                ),
+                // LOCAL, of which addresses are ignored
+                None => (0x20, 0x00, vec![]),
+            };
+            let len = body.len() + if addrs.is_some() { 4 } else { 0 };
+            header.extend([command, family]);
+            header.extend((len as u16).to_be_bytes());
+            header.extend(body);
+            if addrs.is_some() {
+                header.extend(ports.0.to_be_bytes());
+                header.extend(ports.1.to_be_bytes());
+            }
+            header
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Timeout of reading a header, not to keep connections of stalled proxies.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V1_PREFIX: &[u8] = b"PROXY";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Reads a header of either version, returning the source and destination addresses, or `None`
+/// if the proxy tells no addresses, like on its health checks.
+pub async fn read_header<R: AsyncRead + Unpin>(
+    r: &mut R,
+) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    // reads no further than the header, leaving the rest to the provider
+    let mut prefix = [0; 5];
+    r.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LEN {
+                return Err(invalid("too long header"));
+            }
+            line.push(r.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| invalid("non-ASCII header"))?;
+        return parse_v1(line).ok_or_else(|| invalid("malformed header"));
+    }
+
+    if prefix != V2_SIGNATURE[..5] {
+        return Err(invalid("no header"));
+    }
+    let mut rest = [0; 11];
+    r.read_exact(&mut rest).await?;
+    if rest[..7] != V2_SIGNATURE[5..] {
+        return Err(invalid("no header"));
+    }
+
+    let (version, command, family) = (rest[7] >> 4, rest[7] & 0xf, rest[8] >> 4);
+    let mut addrs = vec![0; u16::from_be_bytes([rest[9], rest[10]]).into()];
+    r.read_exact(&mut addrs).await?;
+    if version != 2 {
+        return Err(invalid("unsupported version"));
+    }
+
+    match (command, family) {
+        // LOCAL
+        (0, _) => Ok(None),
+        // PROXY over AF_INET or AF_INET6
+        (1, 1 | 2) => parse_v2_addrs(family, &addrs)
+            .map(Some)
+            .ok_or_else(|| invalid("malformed addresses")),
+        // PROXY over AF_UNIX or unspecified, of which addresses are unusable
+        (1, _) => Ok(None),
+        _ => Err(invalid("unsupported command")),
+    }
+}
+
+/// Parses a header of version 1, like `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n`.
+fn parse_v1(line: &str) -> Option<Option<(SocketAddr, SocketAddr)>> {
+    let mut fields = line.strip_suffix("\r\n")?.split(' ');
+    if fields.next()? != "PROXY" {
+        return None;
+    }
+
+    let family = fields.next()?;
+    if family == "UNKNOWN" {
+        return Some(None);
+    }
+
+    let src: IpAddr = fields.next()?.parse().ok()?;
+    let dst: IpAddr = fields.next()?.parse().ok()?;
+    let sport = fields.next()?.parse().ok()?;
+    let dport = fields.next()?.parse().ok()?;
+    let valid = match family {
+        "TCP4" => src.is_ipv4() && dst.is_ipv4(),
+        "TCP6" => src.is_ipv6() && dst.is_ipv6(),
+        _ => false,
+    };
+    if !valid || fields.next().is_some() {
+        return None;
+    }
+
+    Some(Some((
+        SocketAddr::new(src, sport),
+        SocketAddr::new(dst, dport),
+    )))
+}
+
+/// Parses addresses of version 2, followed by TLVs, which are ignored.
+fn parse_v2_addrs(family: u8, addrs: &[u8]) -> Option<(SocketAddr, SocketAddr)> {
+    let (src, dst, ports) = match family {
+        1 => {
+            let src: [u8; 4] = addrs.get(0..4)?.try_into().ok()?;
+            let dst: [u8; 4] = addrs.get(4..8)?.try_into().ok()?;
+            let ips = (Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into());
+            (ips.0, ips.1, addrs.get(8..12)?)
+        }
+        2 => {
+            let src: [u8; 16] = addrs.get(0..16)?.try_into().ok()?;
+            let dst: [u8; 16] = addrs.get(16..32)?.try_into().ok()?;
+            let ips = (Ipv6Addr::from(src).into(), Ipv6Addr::from(dst).into());
+            (ips.0, ips.1, addrs.get(32..36)?)
+        }
+        _ => return None,
+    };
+
+    let sport = u16::from_be_bytes([ports[0], ports[1]]);
+    let dport = u16::from_be_bytes([ports[2], ports[3]]);
+    Some((SocketAddr::new(src, sport), SocketAddr::new(dst, dport)))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inbound::Metadata;
+    use tokio::io::AsyncWriteExt;
+    use tower::{Service as _, ServiceExt};
+
+    async fn read(header: &[u8]) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+        let data = [header, b"rest"].concat();
+        let mut r = &data[..];
+        let res = read_header(&mut r).await;
+        if res.is_ok() {
+            assert_eq!(r, b"rest");
+        }
+        res
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let v4 = Some((
+            "192.0.2.1:56324".parse().unwrap(),
+            "192.0.2.2:443".parse().unwrap(),
+        ));
+        let v1 = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n";
+        assert_eq!(read(v1).await.unwrap(), v4);
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert!(read(b"PROXY TCP6 192.0.2.1 192.0.2.2 56324 443\r\n")
+            .await
+            .is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n").await.is_err());
+
+        let v2 = [
+            V2_SIGNATURE,
+            &[
+                0x21, 0x11, 0, 12, 192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb,
+            ],
+        ]
+        .concat();
+        assert_eq!(read(&v2).await.unwrap(), v4);
+
+        let local = [V2_SIGNATURE, &[0x20, 0x00, 0, 0]].concat();
+        assert_eq!(read(&local).await.unwrap(), None);
+
+        let v6 = [
+            V2_SIGNATURE,
+            &[0x21, 0x21, 0, 36],
+            &[0; 32],
+            &[0, 80, 0, 81],
+        ]
+        .concat();
+        assert_eq!(
+            read(&v6).await.unwrap(),
+            Some(("[::]:80".parse().unwrap(), "[::]:81".parse().unwrap()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_encode() {
+        let src = "192.0.2.1:56324".parse().unwrap();
+        let dst = "192.0.2.2:443".parse().unwrap();
+        assert_eq!(
+            encode(Version::V1, Some(src), dst),
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n"
+        );
+        assert_eq!(encode(Version::V1, None, dst), b"PROXY UNKNOWN\r\n");
+
+        for version in [Version::V1, Version::V2] {
+            let header = encode(version, Some(src), dst);
+            assert_eq!(read(&header).await.unwrap(), Some((src, dst)));
+
+            let v6 = "[2001:db8::1]:443".parse().unwrap();
+            let mapped = "[::ffff:192.0.2.1]:56324".parse().unwrap();
+            let header = encode(version, Some(src), v6);
+            assert_eq!(read(&header).await.unwrap(), Some((mapped, v6)));
+
+            let header = encode(version, None, dst);
+            assert_eq!(read(&header).await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_service() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let inner = tower::service_fn(move |mut conn: Connection| {
+            let tx = tx.clone();
+            async move {
+                let mut buf = vec![];
+                conn.io.read_to_end(&mut buf).await?;
+                tx.send((conn.meta.peer_addr, buf)).unwrap();
+                Ok::<_, Error>(())
+            }
+        });
+        let mut service =
+            ProxyProtocol::new(Service::new(inner), vec!["10.0.0.0/8".parse().unwrap()]);
+
+        let header = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nping";
+        for (peer, expected) in [
+            ("10.0.0.1:1000", "192.0.2.1:56324"),
+            ("[::ffff:10.0.0.1]:1000", "192.0.2.1:56324"),
+        ] {
+            let (mut client, server) = tokio::io::duplex(1024);
+            client.write_all(header).await.unwrap();
+            drop(client);
+            let meta = Metadata {
+                peer_addr: Some(peer.parse().unwrap()),
+                ..Default::default()
+            };
+            let conn = Connection::new(server, meta).boxed();
+            service.ready().await.unwrap().call(conn).await.unwrap();
+
+            let (addr, buf) = rx.recv().await.unwrap();
+            assert_eq!(addr, Some(expected.parse().unwrap()));
+            assert_eq!(buf, b"ping");
+        }
+
+        // passes connections of untrusted sources as they are
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(header).await.unwrap();
+        drop(client);
+        let meta = Metadata {
+            peer_addr: Some("192.0.2.3:1000".parse().unwrap()),
+            ..Default::default()
+        };
+        let conn = Connection::new(server, meta).boxed();
+        service.ready().await.unwrap().call(conn).await.unwrap();
+
+        let (addr, buf) = rx.recv().await.unwrap();
+        assert_eq!(addr, Some("192.0.2.3:1000".parse().unwrap()));
+        assert_eq!(buf, header);
+    }
+}
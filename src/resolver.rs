@@ -0,0 +1,261 @@
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use hickory_resolver::config::{NameServerConfig, ResolverConfig};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::xfer::Protocol;
+use hickory_resolver::TokioResolver;
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::io;
+use tokio::net::lookup_host;
+
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>>;
+}
+
+/// Resolves names with the resolver of the operating system.
+#[derive(Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        lookup_host((host, port)).map_ok(Iterator::collect).boxed()
+    }
+}
+
+/// Upstream server of [`DnsResolver`].
+///
+/// Written as `[<scheme>://]<ip>[:<port>][/<path>][#<name>]`, where scheme is one of `udp`
+/// (default), `tcp`, `tls` and `https`. `name` is the name of the server certificate and is
+/// required for `tls` and `https`. `path` is only valid for `https` and defaults to `/dns-query`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct NameServer(NameServerConfig);
+
+impl TryFrom<String> for NameServer {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FromStr for NameServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocol, rest) = match s.split_once("://") {
+            Some(("udp", rest)) => (Protocol::Udp, rest),
+            Some(("tcp", rest)) => (Protocol::Tcp, rest),
+            Some(("tls", rest)) => (Protocol::Tls, rest),
+            Some(("https", rest)) => (Protocol::Https, rest),
+            Some((scheme, _)) => return Err(format!("unsupported scheme `{scheme}`")),
+            None => (Protocol::Udp, s),
+        };
+
+        let (rest, name) = match rest.split_once('#') {
+            Some((rest, name)) => (rest, Some(name.to_string())),
+            None => (rest, None),
+        };
+
+        let (host, path) = match rest.split_once('/') {
+            Some((host, path)) => (host, Some(format!("/{path}"))),
+            None => (rest, None),
+        };
+
+        let addr = match (host.parse::<SocketAddr>(), protocol) {
+            (Ok(addr), _) => addr,
+            (Err(_), protocol) => {
+                let ip = host
+                    .strip_prefix('[')
+                    .and_then(|h| h.strip_suffix(']'))
+                    .unwrap_or(host)
+                    .parse::<IpAddr>()
+                    .map_err(|e| format!("invalid address `{host}`: {e}"))?;
+                let port = match protocol {
+                    Protocol::Tls => 853,
+                    Protocol::Https => 443,
+                    _ => 53,
+                };
+                SocketAddr::new(ip, port)
+            }
+        };
+
+        if name.is_none() && matches!(protocol, Protocol::Tls | Protocol::Https) {
+            return Err(format!("server name is required for `{protocol}`"));
+        }
+        if path.is_some() && protocol != Protocol::Https {
+            return Err(format!("path is not allowed for `{protocol}`"));
+        }
+
+        let mut config = NameServerConfig::new(addr, protocol);
+        config.tls_dns_name = name;
+        config.http_endpoint = path;
+        Ok(Self(config))
+    }
+}
+
+/// Resolves names by querying upstream servers directly.
+///
+/// Answers are cached for their TTL and negative answers for the TTL of the SOA record.
+pub struct DnsResolver {
+    inner: TokioResolver,
+}
+
+impl DnsResolver {
+    pub fn new(servers: impl IntoIterator<Item = NameServer>, cache_size: usize) -> Self {
+        let mut config = ResolverConfig::new();
+        for server in servers {
+            config.add_name_server(server.0);
+        }
+
+        let mut builder =
+            TokioResolver::builder_with_config(config, TokioConnectionProvider::default());
+        builder.options_mut().cache_size = cache_size;
+
+        Self {
+            inner: builder.build(),
+        }
+    }
+}
+
+impl Resolver for DnsResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        async move {
+            match self.inner.lookup_ip(host).await {
+                Ok(lookup) => Ok(lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect()),
+                Err(e) if e.is_no_records_found() => {
+                    Err(io::Error::new(io::ErrorKind::NotFound, e))
+                }
+                Err(e) => Err(io::Error::other(e)),
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use hickory_resolver::proto::rr::rdata::{A, SOA};
+    use hickory_resolver::proto::rr::{Name, RData, Record, RecordType};
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::UdpSocket;
+
+    type Queries = Arc<Mutex<HashMap<(String, RecordType), usize>>>;
+
+    /// Answers `A` queries for `found.test.` and `NXDOMAIN` for anything else.
+    async fn serve(socket: UdpSocket, queries: Queries) {
+        let mut buf = vec![0; 512];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let req = Message::from_vec(&buf[..len]).unwrap();
+            let query = req.queries()[0].clone();
+            let name = query.name().to_ascii().to_lowercase();
+
+            *queries
+                .lock()
+                .unwrap()
+                .entry((name.clone(), query.query_type()))
+                .or_default() += 1;
+
+            let mut res = Message::new();
+            res.set_id(req.id())
+                .set_message_type(MessageType::Response)
+                .set_recursion_available(true)
+                .set_authoritative(true)
+                .add_query(query.clone());
+
+            if name == "found.test." {
+                if query.query_type() == RecordType::A {
+                    res.add_answer(Record::from_rdata(
+                        query.name().clone(),
+                        300,
+                        RData::A(A(Ipv4Addr::new(192, 0, 2, 1))),
+                    ));
+                }
+            } else {
+                let zone = Name::from_ascii("test.").unwrap();
+                let soa = SOA::new(zone.clone(), zone.clone(), 1, 3600, 600, 86400, 300);
+                res.set_response_code(ResponseCode::NXDomain)
+                    .add_name_server(Record::from_rdata(zone, 300, RData::SOA(soa)));
+            }
+
+            socket.send_to(&res.to_vec().unwrap(), peer).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_name_server_from_str() {
+        let ns = "192.0.2.1".parse::<NameServer>().unwrap().0;
+        assert_eq!(ns.socket_addr, "192.0.2.1:53".parse().unwrap());
+        assert_eq!(ns.protocol, Protocol::Udp);
+
+        let ns = "tcp://[2001:db8::1]:5353".parse::<NameServer>().unwrap().0;
+        assert_eq!(ns.socket_addr, "[2001:db8::1]:5353".parse().unwrap());
+        assert_eq!(ns.protocol, Protocol::Tcp);
+
+        let ns = "tls://192.0.2.1#dns.example"
+            .parse::<NameServer>()
+            .unwrap()
+            .0;
+        assert_eq!(ns.socket_addr, "192.0.2.1:853".parse().unwrap());
+        assert_eq!(ns.tls_dns_name.as_deref(), Some("dns.example"));
+
+        let ns = "https://192.0.2.1/resolve#dns.example"
+            .parse::<NameServer>()
+            .unwrap()
+            .0;
+        assert_eq!(ns.socket_addr, "192.0.2.1:443".parse().unwrap());
+        assert_eq!(ns.http_endpoint.as_deref(), Some("/resolve"));
+
+        assert!("tls://192.0.2.1".parse::<NameServer>().is_err());
+        assert!("tcp://192.0.2.1/path".parse::<NameServer>().is_err());
+        assert!("quic://192.0.2.1".parse::<NameServer>().is_err());
+        assert!("dns.example".parse::<NameServer>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dns_resolver() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Queries::default();
+        tokio::spawn(serve(socket, Arc::clone(&queries)));
+
+        let resolver = DnsResolver::new([addr.to_string().parse().unwrap()], 64);
+
+        for _ in 0..2 {
+            let addrs = resolver.resolve("found.test.", 80).await.unwrap();
+            assert_eq!(addrs, vec!["192.0.2.1:80".parse().unwrap()]);
+        }
+        assert_eq!(
+            queries.lock().unwrap()[&("found.test.".to_string(), RecordType::A)],
+            1
+        );
+
+        for _ in 0..2 {
+            let e = resolver.resolve("missing.test.", 80).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        }
+        assert_eq!(
+            queries.lock().unwrap()[&("missing.test.".to_string(), RecordType::A)],
+            1
+        );
+    }
+}
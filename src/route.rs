@@ -0,0 +1,805 @@
+use crate::connector::{BoxIo, Connector};
+use crate::inbound::{Metadata, PeerCred};
+use crate::proxy_protocol::Version;
+use crate::upstream::{Proxy, Upstream};
+use crate::{Address, DialError, Dialer};
+use anyhow::{anyhow, Context as _, Result};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use ipnet::IpNet;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io;
+use tracing::debug;
+
+/// Properties of a session rules are matched against.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    /// Address of the client.
+    pub peer_addr: Option<SocketAddr>,
+
+    /// Credentials of the client process, if connected over a Unix domain socket.
+    pub peer_cred: Option<PeerCred>,
+
+    /// Name of the user the client authenticated as.
+    pub user: Option<String>,
+
+    /// Name of the provider serving the session.
+    pub provider: &'static str,
+}
+
+impl Context {
+    pub fn new(provider: &'static str, meta: &Metadata) -> Self {
+        Self {
+            peer_addr: meta.peer_addr,
+            peer_cred: meta.peer_cred.clone(),
+            user: None,
+            provider,
+        }
+    }
+}
+
+/// What to do with a matched session.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Connects directly to the destination.
+    #[default]
+    Direct,
+
+    /// Connects through the named upstream proxy.
+    Upstream(String),
+
+    /// Connects directly to the destination from the named source address.
+    Source(String),
+
+    /// Refuses to connect.
+    Reject,
+}
+
+/// Routing configuration, usually read from a TOML file:
+///
+/// ```toml
+/// default = "direct"
+///
+/// [upstreams]
+/// corp = "http://proxy.corp.example:3128"
+///
+/// [sources]
+/// egress2 = "192.0.2.20"
+///
+/// [[rules]]
+/// domain_suffix = ["corp.example"]
+/// action = { upstream = "corp" }
+///
+/// [[rules]]
+/// cidr = ["10.0.0.0/8"]
+/// port = [22, "8000-8999"]
+/// action = { upstream = "corp" }
+///
+/// [[rules]]
+/// uid = [1001]
+/// domain_suffix = ["example.com"]
+/// action = "direct"
+///
+/// [[rules]]
+/// domain = ["backend.internal"]
+/// action = "direct"
+/// proxy_protocol = 2
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Action of sessions no rule matched.
+    pub default: Action,
+    pub upstreams: HashMap<String, Upstream>,
+    pub sources: HashMap<String, IpAddr>,
+    pub rules: Vec<Rule>,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&s).with_context(|| format!("failed to parse {}", path.display()))
+    }
+}
+
+/// Set of conditions and the action taken when all of them are met.
+///
+/// Each condition is met when any of its values matches. Conditions left empty always match.
+/// `domain`, `domain_suffix` and `domain_regex` form a single condition, which only matches
+/// destinations given by name. `cidr` matches destinations given by address, or by name resolving
+/// to any address in its ranges, which is resolved only when the other conditions are met. Names
+/// resolved while routing are connected to directly by the addresses matched, not resolved again.
+/// `uid` and `gid` only match local clients, connected over Unix domain sockets or, on Linux,
+/// TCP loopback.
+///
+/// `proxy_protocol` sends a PROXY protocol header of the version, `1` or `2`, telling the address
+/// of the client to the destination. It is only allowed with `direct` and `source` actions.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RuleConfig")]
+pub struct Rule {
+    domains: Vec<DomainMatcher>,
+    cidrs: Vec<IpNet>,
+    ports: Vec<(u16, u16)>,
+    clients: Vec<IpNet>,
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+    users: Vec<String>,
+    providers: Vec<String>,
+    action: Action,
+    proxy_protocol: Option<Version>,
+}
+
+#[derive(Debug)]
+enum DomainMatcher {
+    Exact(String),
+    Suffix(String),
+    Regex(Regex),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    #[serde(default)]
+    domain: Vec<String>,
+    #[serde(default)]
+    domain_suffix: Vec<String>,
+    #[serde(default)]
+    domain_regex: Vec<String>,
+    #[serde(default)]
+    cidr: Vec<IpNet>,
+    #[serde(default)]
+    port: Vec<PortRange>,
+    #[serde(default)]
+    client: Vec<IpNet>,
+    #[serde(default)]
+    uid: Vec<u32>,
+    #[serde(default)]
+    gid: Vec<u32>,
+    #[serde(default)]
+    user: Vec<String>,
+    #[serde(default)]
+    provider: Vec<String>,
+    action: Action,
+    proxy_protocol: Option<Version>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRange {
+    Single(u16),
+    Range(String),
+}
+
+impl TryFrom<RuleConfig> for Rule {
+    type Error = String;
+
+    fn try_from(config: RuleConfig) -> Result<Self, Self::Error> {
+        let normalize = |s: String| s.trim_end_matches('.').to_ascii_lowercase();
+
+        let domains = config
+            .domain
+            .into_iter()
+            .map(|s| Ok(DomainMatcher::Exact(normalize(s))))
+            .chain(
+                config
+                    .domain_suffix
+                    .into_iter()
+                    .map(|s| Ok(DomainMatcher::Suffix(normalize(s)))),
+            )
+            .chain(config.domain_regex.into_iter().map(|s| {
+                Regex::new(&s)
+                    .map(DomainMatcher::Regex)
+                    .map_err(|e| e.to_string())
+            }))
+            .collect::<Result<_, String>>()?;
+
+        let ports = config
+            .port
+            .into_iter()
+            .map(|p| match p {
+                PortRange::Single(port) => Ok((port, port)),
+                PortRange::Range(s) => s
+                    .split_once('-')
+                    .and_then(|(a, b)| Some((a.trim().parse().ok()?, b.trim().parse().ok()?)))
+                    .filter(|(a, b)| a <= b)
+                    .ok_or_else(|| format!("invalid port range `{s}`")),
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self {
+            domains,
+            cidrs: config.cidr,
+            ports,
+            clients: config.client,
+            uids: config.uid,
+            gids: config.gid,
+            users: config.user,
+            providers: config.provider,
+            action: config.action,
+            proxy_protocol: config.proxy_protocol,
+        })
+    }
+}
+
+impl Rule {
+    /// Returns whether the conditions other than `cidr` are met.
+    fn matches(&self, ctx: &Context, addr: &Address) -> bool {
+        fn any<T>(values: &[T], f: impl FnMut(&T) -> bool) -> bool {
+            values.is_empty() || values.iter().any(f)
+        }
+
+        let domain = match addr {
+            Address::Domain(domain, _) => Some(domain.trim_end_matches('.').to_ascii_lowercase()),
+            Address::Ip(_) => None,
+        };
+        let port = addr.port();
+
+        let domain_matches = |m: &DomainMatcher| matches!(&domain, Some(d) if m.matches(d));
+        let client_matches =
+            |c: &IpNet| matches!(ctx.peer_addr, Some(a) if c.contains(&a.ip().to_canonical()));
+
+        any(&self.domains, domain_matches)
+            && any(&self.ports, |&(lo, hi)| (lo..=hi).contains(&port))
+            && any(&self.clients, client_matches)
+            && any(
+                &self.uids,
+                |&u| matches!(&ctx.peer_cred, Some(c) if c.uid == u),
+            )
+            && any(
+                &self.gids,
+                |&g| matches!(&ctx.peer_cred, Some(c) if c.gid == Some(g)),
+            )
+            && any(&self.users, |u| ctx.user.as_ref() == Some(u))
+            && any(&self.providers, |p| p == ctx.provider)
+    }
+}
+
+impl DomainMatcher {
+    fn matches(&self, domain: &str) -> bool {
+        match self {
+            Self::Exact(name) => domain == name,
+            Self::Suffix(suffix) => {
+                domain == suffix
+                    || domain
+                        .strip_suffix(suffix.as_str())
+                        .is_some_and(|d| d.ends_with('.'))
+            }
+            Self::Regex(regex) => regex.is_match(domain),
+        }
+    }
+}
+
+/// Decides how each session connects to its destination.
+pub struct Router {
+    default: Action,
+    rules: Vec<Rule>,
+    direct: Dialer,
+    sources: HashMap<String, Dialer>,
+    upstreams: HashMap<String, Arc<dyn Connector>>,
+}
+
+impl Router {
+    pub fn new(config: Config, dialer: Dialer) -> Result<Self> {
+        let actions = config
+            .rules
+            .iter()
+            .map(|r| &r.action)
+            .chain([&config.default]);
+        for action in actions {
+            match action {
+                Action::Upstream(name) if !config.upstreams.contains_key(name) => {
+                    return Err(anyhow!("unknown upstream `{name}`"));
+                }
+                Action::Source(name) if !config.sources.contains_key(name) => {
+                    return Err(anyhow!("unknown source `{name}`"));
+                }
+                _ => {}
+            }
+        }
+        for rule in &config.rules {
+            if rule.proxy_protocol.is_some()
+                && !matches!(rule.action, Action::Direct | Action::Source(_))
+            {
+                return Err(anyhow!(
+                    "PROXY protocol is only sent on direct connections, not {:?}",
+                    rule.action
+                ));
+            }
+        }
+
+        let sources = config
+            .sources
+            .into_iter()
+            .map(|(name, ip)| (name, dialer.clone().bind_addr(SocketAddr::new(ip, 0))))
+            .collect();
+
+        // upstreams are trusted and exempt from the destination filter
+        let upstream_dialer = Arc::new(dialer.clone().filter(None));
+        let upstreams = config
+            .upstreams
+            .into_iter()
+            .map(|(name, upstream)| {
+                let mut proxy = Proxy::new(upstream, upstream_dialer.clone());
+                if let Some(timeout) = dialer.deadline() {
+                    proxy = proxy.timeout(timeout);
+                }
+                (name, Arc::new(proxy) as Arc<dyn Connector>)
+            })
+            .collect();
+
+        Ok(Self {
+            default: config.default,
+            rules: config.rules,
+            direct: dialer,
+            sources,
+            upstreams,
+        })
+    }
+
+    /// Returns whether any rule matches the user or group of clients, which TCP clients are
+    /// only known by if identified.
+    pub fn matches_peer_cred(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|r| !r.uids.is_empty() || !r.gids.is_empty())
+    }
+
+    /// Returns the action of the session, along with the addresses resolved while routing.
+    async fn route(&self, ctx: &Context, addr: &Address) -> Route<'_> {
+        let mut resolved: Option<io::Result<Vec<SocketAddr>>> = None;
+        for rule in &self.rules {
+            if !rule.matches(ctx, addr) {
+                continue;
+            }
+            if !rule.cidrs.is_empty() {
+                if resolved.is_none() {
+                    let res = self.direct.resolve(addr).await;
+                    if let Err(e) = &res {
+                        debug!("failed to resolve {addr} for routing: {e}");
+                    }
+                    resolved = Some(res);
+                }
+                let Some(Ok(addrs)) = &resolved else {
+                    continue;
+                };
+                let matched = addrs
+                    .iter()
+                    .copied()
+                    .filter(|a| {
+                        rule.cidrs
+                            .iter()
+                            .any(|c| c.contains(&a.ip().to_canonical()))
+                    })
+                    .collect::<Vec<_>>();
+                if matched.is_empty() {
+                    continue;
+                }
+                return Route {
+                    action: &rule.action,
+                    proxy_protocol: rule.proxy_protocol,
+                    resolved: Some(Ok(matched)),
+                    by_address: true,
+                };
+            }
+            return Route {
+                action: &rule.action,
+                proxy_protocol: rule.proxy_protocol,
+                resolved,
+                by_address: false,
+            };
+        }
+
+        Route {
+            action: &self.default,
+            proxy_protocol: None,
+            resolved,
+            by_address: false,
+        }
+    }
+
+    /// Connects directly with `dialer` to the addresses resolved while routing, if any, sending a
+    /// PROXY protocol header of `version` if any.
+    async fn dial(
+        dialer: &Dialer,
+        version: Option<Version>,
+        ctx: &Context,
+        addr: &Address,
+        resolved: Option<io::Result<Vec<SocketAddr>>>,
+    ) -> Result<BoxIo, DialError> {
+        let addrs = resolved.transpose()?;
+        if version.is_none() {
+            return dialer.connect_resolved(ctx, addr, addrs).await;
+        }
+
+        let dialer = dialer.clone().proxy_protocol(version);
+        dialer.connect_resolved(ctx, addr, addrs).await
+    }
+}
+
+/// Action decided for a session.
+#[derive(Debug)]
+struct Route<'a> {
+    action: &'a Action,
+
+    /// Version of the PROXY protocol to send, if any.
+    proxy_protocol: Option<Version>,
+
+    /// Addresses the destination resolved to while routing, connected to instead of resolving it
+    /// again, which may give addresses the rules did not check. Only the addresses in range if
+    /// matched `by_address`.
+    resolved: Option<io::Result<Vec<SocketAddr>>>,
+
+    /// Whether the rule matched the destination by its address.
+    by_address: bool,
+}
+
+impl Connector for Router {
+    fn connect<'a>(
+        &'a self,
+        ctx: &'a Context,
+        addr: &'a Address,
+    ) -> BoxFuture<'a, Result<BoxIo, DialError>> {
+        async move {
+            let route = self.route(ctx, addr).await;
+            debug!("routing {addr} to {:?}", route.action);
+
+            match route.action {
+                Action::Direct => {
+                    Self::dial(
+                        &self.direct,
+                        route.proxy_protocol,
+                        ctx,
+                        addr,
+                        route.resolved,
+                    )
+                    .await
+                }
+                Action::Upstream(name) => match route.resolved {
+                    // upstreams resolve names on their own, so the address matched is passed on
+                    Some(Ok(addrs)) if route.by_address => {
+                        let addr = Address::Ip(addrs[0]);
+                        self.upstreams[name].connect(ctx, &addr).await
+                    }
+                    _ => self.upstreams[name].connect(ctx, addr).await,
+                },
+                Action::Source(name) => {
+                    let dialer = &self.sources[name];
+                    Self::dial(dialer, route.proxy_protocol, ctx, addr, route.resolved).await
+                }
+                Action::Reject => Err(DialError::Rejected),
+            }
+        }
+        .boxed()
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new(Config::default(), Dialer::default()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::Resolver;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    const CONFIG: &str = r#"
+        default = "reject"
+
+        [upstreams]
+        corp = "http://proxy.corp.example:3128"
+
+        [sources]
+        egress2 = "192.0.2.20"
+
+        [[rules]]
+        domain_suffix = ["corp.example"]
+        action = { upstream = "corp" }
+
+        [[rules]]
+        domain = ["www.example.com"]
+        domain_regex = ['^cdn\d+\.example\.net$']
+        port = [80, "443-444"]
+        action = "direct"
+
+        [[rules]]
+        cidr = ["198.51.100.0/24"]
+        client = ["127.0.0.0/8"]
+        action = { source = "egress2" }
+
+        [[rules]]
+        user = ["alice"]
+        provider = ["socks"]
+        action = "direct"
+
+        [[rules]]
+        uid = [1001]
+        domain_suffix = ["example.org"]
+        action = "direct"
+
+        [[rules]]
+        domain = ["backend.internal"]
+        action = "direct"
+        proxy_protocol = 2
+    "#;
+
+    /// Resolver of names in a `cidr` range of the rules.
+    struct Static;
+
+    impl Resolver for Static {
+        fn resolve<'a>(
+            &'a self,
+            host: &'a str,
+            port: u16,
+        ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+            let res = match host {
+                "egress.example" => Ok(vec![SocketAddr::new([198, 51, 100, 2].into(), port)]),
+                "mixed.example" => Ok(vec![
+                    SocketAddr::new([203, 0, 113, 1].into(), port),
+                    SocketAddr::new([198, 51, 100, 3].into(), port),
+                ]),
+                _ => Err(io::ErrorKind::NotFound.into()),
+            };
+            future::ready(res).boxed()
+        }
+    }
+
+    async fn route(router: &Router, ctx: &Context, host: &str, port: u16) -> Action {
+        router
+            .route(ctx, &Address::new(host, port))
+            .await
+            .action
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn test_route() {
+        let config = toml::from_str(CONFIG).unwrap();
+        let router = Router::new(config, Dialer::default().resolver(Arc::new(Static))).unwrap();
+
+        let ctx = Context {
+            peer_addr: Some("127.0.0.1:10000".parse().unwrap()),
+            peer_cred: None,
+            user: None,
+            provider: "http",
+        };
+        assert_eq!(
+            route(&router, &ctx, "corp.example", 80).await,
+            Action::Upstream("corp".into())
+        );
+        assert_eq!(
+            route(&router, &ctx, "git.Corp.Example.", 22).await,
+            Action::Upstream("corp".into())
+        );
+        assert_eq!(
+            route(&router, &ctx, "xcorp.example", 22).await,
+            Action::Reject
+        );
+        assert_eq!(
+            route(&router, &ctx, "www.example.com", 443).await,
+            Action::Direct
+        );
+        assert_eq!(
+            route(&router, &ctx, "cdn12.example.net", 80).await,
+            Action::Direct
+        );
+        assert_eq!(
+            route(&router, &ctx, "www.example.com", 8080).await,
+            Action::Reject
+        );
+        assert_eq!(
+            route(&router, &ctx, "198.51.100.1", 80).await,
+            Action::Source("egress2".into())
+        );
+
+        let remote = Context {
+            peer_addr: Some("192.0.2.1:10000".parse().unwrap()),
+            ..ctx.clone()
+        };
+        assert_eq!(
+            route(&router, &remote, "198.51.100.1", 80).await,
+            Action::Reject
+        );
+
+        // dual-stack listeners and clients may give IPv4 addresses mapped to IPv6
+        let mapped = Context {
+            peer_addr: Some("[::ffff:127.0.0.1]:10000".parse().unwrap()),
+            ..ctx.clone()
+        };
+        assert_eq!(
+            route(&router, &mapped, "198.51.100.1", 80).await,
+            Action::Source("egress2".into())
+        );
+        assert_eq!(
+            route(&router, &ctx, "[::ffff:198.51.100.1]", 80).await,
+            Action::Source("egress2".into())
+        );
+
+        let alice = Context {
+            user: Some("alice".into()),
+            provider: "socks",
+            ..ctx.clone()
+        };
+        assert_eq!(
+            route(&router, &alice, "example.org", 80).await,
+            Action::Direct
+        );
+        let http = Context {
+            provider: "http",
+            ..alice
+        };
+        assert_eq!(
+            route(&router, &http, "example.org", 80).await,
+            Action::Reject
+        );
+
+        let local = Context {
+            peer_addr: None,
+            peer_cred: Some(PeerCred {
+                uid: 1001,
+                gid: Some(1001),
+                pid: Some(100),
+                program: None,
+            }),
+            ..ctx.clone()
+        };
+        assert_eq!(
+            route(&router, &local, "www.example.org", 443).await,
+            Action::Direct
+        );
+        assert_eq!(
+            route(&router, &local, "example.net", 443).await,
+            Action::Reject
+        );
+        assert_eq!(
+            route(&router, &ctx, "www.example.org", 443).await,
+            Action::Reject
+        );
+
+        // names resolving into the ranges of `cidr` match them
+        assert_eq!(
+            route(&router, &ctx, "egress.example", 80).await,
+            Action::Source("egress2".into())
+        );
+        assert_eq!(
+            route(&router, &remote, "egress.example", 80).await,
+            Action::Reject
+        );
+        assert_eq!(
+            route(&router, &ctx, "unknown.example", 80).await,
+            Action::Reject
+        );
+
+        let addr = Address::new("backend.internal", 80);
+        let route = router.route(&ctx, &addr).await;
+        assert_eq!(route.action, &Action::Direct);
+        assert_eq!(route.proxy_protocol, Some(Version::V2));
+        let addr = Address::new("www.example.com", 80);
+        assert_eq!(router.route(&ctx, &addr).await.proxy_protocol, None);
+
+        // only the addresses in range are connected to
+        let addr = Address::new("mixed.example", 80);
+        let route = router.route(&ctx, &addr).await;
+        assert_eq!(route.action, &Action::Source("egress2".into()));
+        assert_eq!(
+            route.resolved.unwrap().unwrap(),
+            ["198.51.100.3:80".parse().unwrap()]
+        );
+
+        assert!(router.matches_peer_cred());
+        assert!(!Router::default().matches_peer_cred());
+    }
+
+    /// Resolver counting the lookups, of names resolving to a loopback address and another.
+    struct Counting(AtomicUsize, SocketAddr);
+
+    impl Resolver for Counting {
+        fn resolve<'a>(&'a self, _: &'a str, _: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            let addrs = vec!["192.0.2.1:80".parse().unwrap(), self.1];
+            future::ready(Ok(addrs)).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_resolved() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = Arc::new(Counting(
+            AtomicUsize::new(0),
+            listener.local_addr().unwrap(),
+        ));
+        let config = toml::from_str(
+            r#"
+            default = "reject"
+
+            [[rules]]
+            cidr = ["127.0.0.0/8"]
+            action = "direct"
+            "#,
+        )
+        .unwrap();
+        let router = Router::new(config, Dialer::default().resolver(resolver.clone())).unwrap();
+
+        let addr = Address::new("loopback.example", 80);
+        router.connect(&Context::default(), &addr).await.unwrap();
+        assert_eq!(resolver.0.load(Ordering::Relaxed), 1);
+
+        let config = toml::from_str(
+            r#"
+            [[rules]]
+            cidr = ["127.0.0.0/8"]
+            action = "reject"
+            "#,
+        )
+        .unwrap();
+        let router = Router::new(config, Dialer::default().resolver(resolver)).unwrap();
+        assert!(matches!(
+            router.connect(&Context::default(), &addr).await,
+            Err(DialError::Rejected)
+        ));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = toml::from_str(r#"default = { upstream = "none" }"#).unwrap();
+        assert!(Router::new(config, Dialer::default()).is_err());
+
+        let config = toml::from_str(
+            r#"
+            [[rules]]
+            action = { source = "none" }
+            "#,
+        )
+        .unwrap();
+        assert!(Router::new(config, Dialer::default()).is_err());
+
+        let config = toml::from_str(
+            r#"
+            [upstreams]
+            corp = "http://proxy.corp.example:3128"
+
+            [[rules]]
+            action = { upstream = "corp" }
+            proxy_protocol = 1
+            "#,
+        )
+        .unwrap();
+        assert!(Router::new(config, Dialer::default()).is_err());
+
+        assert!(toml::from_str::<Config>(
+            r#"
+            [[rules]]
+            domain_regex = ["("]
+            action = "direct"
+            "#
+        )
+        .is_err());
+        assert!(toml::from_str::<Config>(
+            r#"
+            [[rules]]
+            port = ["2-1"]
+            action = "direct"
+            "#
+        )
+        .is_err());
+        assert!(toml::from_str::<Config>(
+            r#"
+            [[rules]]
+            action = "direct"
+            proxy_protocol = 3
+            "#
+        )
+        .is_err());
+    }
+}
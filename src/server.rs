@@ -0,0 +1,792 @@
+use crate::{Auth, ClientLimit, Connection, Connector, Dialer, Service};
+use anyhow::{anyhow, Context as _, Result};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tower::{Service as _, ServiceExt};
+use tracing::{debug, info, warn};
+
+/// Source of inbound connections.
+pub trait Listener: Send + Sync + 'static {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Connection>>;
+
+    /// Describes where connections are accepted, for logging.
+    fn local_addr(&self) -> io::Result<String>;
+
+    /// Returns the file descriptor of the socket, if any, to pass it to another process.
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        None
+    }
+}
+
+impl<L: Listener + ?Sized> Listener for Arc<L> {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Connection>> {
+        L::accept(self)
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        L::local_addr(self)
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        L::raw_fd(self)
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Connection>> {
+        TcpListener::accept(self)
+            .map_ok(|(stream, _)| Connection::from(stream).boxed())
+            .boxed()
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        TcpListener::local_addr(self).map(|a| a.to_string())
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        Some(std::os::fd::AsRawFd::as_raw_fd(self))
+    }
+}
+
+#[cfg(unix)]
+impl Listener for tokio::net::UnixListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Connection>> {
+        tokio::net::UnixListener::accept(self)
+            .map_ok(|(stream, _)| Connection::from(stream).boxed())
+            .boxed()
+    }
+
+    /// Describes the address as `unix:<path>`, or `unix:@<name>` if it is abstract.
+    fn local_addr(&self) -> io::Result<String> {
+        let addr =
+            std::os::unix::net::SocketAddr::from(tokio::net::UnixListener::local_addr(self)?);
+        if let Some(path) = addr.as_pathname() {
+            return Ok(format!("unix:{}", path.display()));
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(name) = std::os::linux::net::SocketAddrExt::as_abstract_name(&addr) {
+            return Ok(format!("unix:@{}", String::from_utf8_lossy(name)));
+        }
+
+        Ok("unix:".to_string())
+    }
+
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        Some(std::os::fd::AsRawFd::as_raw_fd(self))
+    }
+}
+
+/// Settings of a listener, passed to its provider.
+#[derive(Clone)]
+pub struct Settings {
+    /// Connector of outbound connections.
+    pub connector: Arc<dyn Connector>,
+
+    /// Credentials clients must authenticate with, if any.
+    pub auth: Option<Arc<Auth>>,
+
+    /// Limit of concurrent sessions of each client, over which connections are rejected.
+    pub client_limit: Option<Arc<ClientLimit>>,
+
+    /// Whether to tell servers the addresses of clients, in `Forwarded` headers of HTTP.
+    pub forwarded: bool,
+}
+
+impl Settings {
+    pub fn new(connector: Arc<dyn Connector>) -> Self {
+        Self {
+            connector,
+            auth: None,
+            client_limit: None,
+            forwarded: false,
+        }
+    }
+
+    pub fn auth(mut self, auth: Option<Arc<Auth>>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn client_limit(mut self, limit: Option<Arc<ClientLimit>>) -> Self {
+        self.client_limit = limit;
+        self
+    }
+
+    pub fn forwarded(mut self, forwarded: bool) -> Self {
+        self.forwarded = forwarded;
+        self
+    }
+}
+
+/// Protocol served on listeners.
+pub trait Provider: Send + Sync {
+    /// Creates a service serving connections of a listener configured with `settings`.
+    fn service(&self, settings: &Settings) -> Service;
+}
+
+impl<F> Provider for F
+where
+    F: Fn(&Settings) -> Service + Send + Sync,
+{
+    fn service(&self, settings: &Settings) -> Service {
+        self(settings)
+    }
+}
+
+/// Providers looked up by name.
+#[derive(Clone)]
+pub struct Registry {
+    providers: HashMap<String, Arc<dyn Provider>>,
+}
+
+impl Default for Registry {
+    /// Creates a registry of the built-in providers, `http` and `socks`.
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register("http", |s: &Settings| {
+            let service = crate::http::Service::new(s.connector.clone());
+            Service::new(
+                service
+                    .auth(s.auth.clone())
+                    .client_limit(s.client_limit.clone())
+                    .forwarded(s.forwarded),
+            )
+        });
+        registry.register("socks", |s: &Settings| {
+            let service = crate::socks::provider::Service::new(s.connector.clone());
+            Service::new(
+                service
+                    .auth(s.auth.clone())
+                    .client_limit(s.client_limit.clone()),
+            )
+        });
+        registry
+    }
+}
+
+impl Registry {
+    pub fn empty() -> Self {
+        Self {
+            providers: HashMap::new(),
+        }
+    }
+
+    /// Registers `provider` as `name`, replacing the one already registered, if any.
+    pub fn register(&mut self, name: impl Into<String>, provider: impl Provider + 'static) {
+        self.providers.insert(name.into(), Arc::new(provider));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn Provider>> {
+        self.providers.get(name)
+    }
+
+    pub fn create(&self, name: &str, settings: &Settings) -> Result<Service> {
+        self.get(name)
+            .map(|p| p.service(settings))
+            .ok_or_else(|| anyhow!("unknown provider: `{name}`"))
+    }
+}
+
+/// Listener and the service serving its connections.
+pub type Binding = (Arc<dyn Listener>, Service);
+
+enum Served {
+    Provider(String),
+    Service(Service),
+}
+
+pub struct Builder {
+    registry: Registry,
+    connector: Option<Arc<dyn Connector>>,
+    listeners: Vec<(Box<dyn Listener>, Served)>,
+    shutdown: Option<BoxFuture<'static, ()>>,
+    force_shutdown: Option<BoxFuture<'static, ()>>,
+    drain_timeout: Duration,
+    max_sessions: Option<NonZeroUsize>,
+    reload: Option<BoxStream<'static, Vec<Binding>>>,
+}
+
+impl Builder {
+    /// Sets the registry providers are looked up in. Defaults to the built-in providers.
+    pub fn registry(mut self, registry: Registry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Sets the connector of providers looked up by name. Defaults to [`Dialer::default`].
+    pub fn connector(mut self, connector: Arc<dyn Connector>) -> Self {
+        self.connector = Some(connector);
+        self
+    }
+
+    /// Serves connections accepted by `listener` with the provider named `provider`.
+    pub fn listen(mut self, listener: impl Listener, provider: impl Into<String>) -> Self {
+        let served = Served::Provider(provider.into());
+        self.listeners.push((Box::new(listener), served));
+        self
+    }
+
+    /// Serves connections accepted by `listener` with `service`.
+    pub fn listen_service(mut self, listener: impl Listener, service: Service) -> Self {
+        self.listeners
+            .push((Box::new(listener), Served::Service(service)));
+        self
+    }
+
+    /// Stops the server when `signal` completes.
+    ///
+    /// Listeners are stopped accepting connections, and sessions already accepted are waited
+    /// for up to the drain timeout before closed.
+    pub fn shutdown(mut self, signal: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.shutdown = Some(signal.boxed());
+        self
+    }
+
+    /// Closes sessions at once when `signal` completes while waiting for them on shutdown.
+    pub fn force_shutdown(mut self, signal: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.force_shutdown = Some(signal.boxed());
+        self
+    }
+
+    /// Sets how long to wait for sessions to finish on shutdown. Defaults to zero, closing them
+    /// at once.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Limits the number of concurrent sessions of all the listeners.
+    ///
+    /// Connections over the limit wait to be accepted.
+    pub fn max_sessions(mut self, max: Option<NonZeroUsize>) -> Self {
+        self.max_sessions = max;
+        self
+    }
+
+    /// Replaces all the listeners with each set of bindings `bindings` yields.
+    ///
+    /// Listeners are stopped accepting connections, while sessions already accepted continue.
+    /// Listeners kept in the new set continue accepting connections with the new service.
+    pub fn reload(mut self, bindings: impl Stream<Item = Vec<Binding>> + Send + 'static) -> Self {
+        self.reload = Some(bindings.boxed());
+        self
+    }
+
+    pub fn build(self) -> Result<Server> {
+        let connector = self
+            .connector
+            .unwrap_or_else(|| Arc::new(Dialer::default()));
+        let settings = Settings::new(connector);
+
+        let listeners = self
+            .listeners
+            .into_iter()
+            .map(|(listener, served)| {
+                let service = match served {
+                    Served::Provider(name) => self.registry.create(&name, &settings)?,
+                    Served::Service(service) => service,
+                };
+                Ok((listener, service))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Server {
+            listeners,
+            shutdown: self.shutdown.unwrap_or_else(|| future::pending().boxed()),
+            force_shutdown: self
+                .force_shutdown
+                .unwrap_or_else(|| future::pending().boxed()),
+            drain_timeout: self.drain_timeout,
+            sessions_limit: self.max_sessions.map(|n| Arc::new(Semaphore::new(n.get()))),
+            metrics: Arc::default(),
+            reload: self.reload.unwrap_or_else(|| stream::pending().boxed()),
+        })
+    }
+}
+
+/// Proxy server accepting connections on listeners and serving them with providers.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:1080").await?;
+/// juno::Server::builder()
+///     .listen(listener, "socks")
+///     .shutdown(async {
+///         let _ = tokio::signal::ctrl_c().await;
+///     })
+///     .drain_timeout(std::time::Duration::from_secs(30))
+///     .build()?
+///     .run()
+///     .await
+/// # }
+/// ```
+pub struct Server {
+    listeners: Vec<(Box<dyn Listener>, Service)>,
+    shutdown: BoxFuture<'static, ()>,
+    force_shutdown: BoxFuture<'static, ()>,
+    drain_timeout: Duration,
+    sessions_limit: Option<Arc<Semaphore>>,
+    metrics: Arc<Metrics>,
+    reload: BoxStream<'static, Vec<Binding>>,
+}
+
+/// Counters of a server, updated while it runs.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    accepted: AtomicU64,
+    accept_errors: AtomicU64,
+    accept_exhaustions: AtomicU64,
+    sessions: AtomicU64,
+}
+
+impl Metrics {
+    /// Returns the number of connections accepted.
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of failures to accept connections listeners recovered from.
+    pub fn accept_errors(&self) -> u64 {
+        self.accept_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of the accept errors caused by exhausted resources, like the limit of
+    /// open files, on which listeners back off.
+    pub fn accept_exhaustions(&self) -> u64 {
+        self.accept_exhaustions.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of sessions running, including those draining on shutdown.
+    pub fn sessions(&self) -> u64 {
+        self.sessions.load(Ordering::Relaxed)
+    }
+}
+
+/// Session counted in [`Metrics`] until dropped, either finished or closed.
+struct Running(Arc<Metrics>);
+
+impl Running {
+    fn new(metrics: &Arc<Metrics>) -> Self {
+        metrics.sessions.fetch_add(1, Ordering::Relaxed);
+        Self(Arc::clone(metrics))
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// State of a server shared by its listeners.
+#[derive(Clone)]
+struct Shared {
+    sessions_limit: Option<Arc<Semaphore>>,
+    metrics: Arc<Metrics>,
+    /// Sessions spawned by listeners, waited for on shutdown.
+    sessions: TaskTracker,
+    /// Closes the sessions when cancelled.
+    close: CancellationToken,
+}
+
+impl Server {
+    /// Returns the metrics of the server, kept updated after it starts running.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    pub fn builder() -> Builder {
+        Builder {
+            registry: Registry::default(),
+            connector: None,
+            listeners: vec![],
+            shutdown: None,
+            force_shutdown: None,
+            drain_timeout: Duration::ZERO,
+            max_sessions: None,
+            reload: None,
+        }
+    }
+
+    /// Serves until the shutdown signal completes or any listener fails.
+    pub async fn run(self) -> Result<()> {
+        let shared = Shared {
+            sessions_limit: self.sessions_limit,
+            metrics: self.metrics,
+            sessions: TaskTracker::new(),
+            close: CancellationToken::new(),
+        };
+        let mut tasks = JoinSet::new();
+        for (listener, service) in self.listeners {
+            tasks.spawn(listen(listener, service, shared.clone()));
+        }
+
+        let mut shutdown = self.shutdown;
+        let mut reload = self.reload;
+        let res = loop {
+            tokio::select! {
+                _ = &mut shutdown => break Ok(()),
+                Some(bindings) = reload.next() => {
+                    tasks.shutdown().await;
+                    for (listener, service) in bindings {
+                        tasks.spawn(listen(Box::new(listener), service, shared.clone()));
+                    }
+                    info!("reloaded listeners");
+                }
+                Some(r) = tasks.join_next() => match r {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => break Err(e),
+                    Err(e) => break Err(e.into()),
+                },
+            }
+        };
+
+        // lets the source of bindings release listeners as well
+        drop(reload);
+        tasks.shutdown().await;
+
+        let sessions = shared.sessions;
+        sessions.close();
+        if !sessions.is_empty() {
+            info!("waiting for {} sessions to finish", sessions.len());
+            let drained = tokio::select! {
+                biased;
+                _ = sessions.wait() => true,
+                _ = tokio::time::sleep(self.drain_timeout) => false,
+                _ = self.force_shutdown => false,
+            };
+            if !drained {
+                warn!("closing {} sessions", sessions.len());
+                shared.close.cancel();
+                sessions.wait().await;
+            }
+        }
+
+        res
+    }
+}
+
+async fn listen(listener: Box<dyn Listener>, mut service: Service, shared: Shared) -> Result<()> {
+    match listener.local_addr() {
+        Ok(addr) => {
+            info!("listening on {addr}");
+        }
+        Err(e) => {
+            warn!("failed to get local address: {e}")
+        }
+    }
+
+    let mut backoff = None;
+    loop {
+        // waits for the service before accepting, not to drop accepted connections on reload
+        let service = service.ready().await?;
+        let permit = match &shared.sessions_limit {
+            Some(limit) => Some(Arc::clone(limit).acquire_owned().await?),
+            None => None,
+        };
+
+        let conn = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) if is_exhausted(&e) => {
+                shared.metrics.accept_errors.fetch_add(1, Ordering::Relaxed);
+                shared
+                    .metrics
+                    .accept_exhaustions
+                    .fetch_add(1, Ordering::Relaxed);
+
+                // backs off not to spin while no resources are released
+                let delay = backoff.map_or(MIN_BACKOFF, |d: Duration| (d * 2).min(MAX_BACKOFF));
+                backoff = Some(delay);
+                // logged periodically from the metrics instead, not to flood the log
+                debug!("failed to accept connection: {e}; retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            Err(e) if is_transient(&e) => {
+                shared.metrics.accept_errors.fetch_add(1, Ordering::Relaxed);
+                debug!("failed to accept connection: {e}");
+                continue;
+            }
+            Err(e) => return Err(e).context("failed to accept connection"),
+        };
+        backoff = None;
+        shared.metrics.accepted.fetch_add(1, Ordering::Relaxed);
+        let running = Running::new(&shared.metrics);
+
+        // takes the ready service to call it in the session
+        let clone = service.clone();
+        let mut service = std::mem::replace(service, clone);
+        let session = async move {
+            match (&conn.meta.peer_addr, &conn.meta.peer_cred) {
+                (Some(addr), Some(cred)) => debug!("connected from {addr} ({cred})"),
+                (Some(addr), None) => debug!("connected from {addr}"),
+                (None, Some(cred)) => debug!("connected from {cred}"),
+                (None, None) => {}
+            }
+            let _ = service.call(conn).await;
+            drop((permit, running));
+        };
+        let close = shared.close.clone();
+        shared.sessions.spawn(async move {
+            tokio::select! {
+                _ = close.cancelled() => {}
+                _ = session => {}
+            }
+        });
+    }
+}
+
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Returns whether accepting failed for the connection only, so the next one can be accepted.
+fn is_transient(e: &io::Error) -> bool {
+    // includes errors pending on the connection, which Linux returns from accept(2)
+    #[cfg(unix)]
+    if let Some(libc::EPROTO | libc::ENOPROTOOPT | libc::EHOSTDOWN | libc::EOPNOTSUPP) =
+        e.raw_os_error()
+    {
+        return true;
+    }
+
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::NetworkDown
+    )
+}
+
+/// Returns whether accepting failed for lack of resources, which may be released later.
+fn is_exhausted(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) = e.raw_os_error() {
+        return true;
+    }
+
+    e.kind() == io::ErrorKind::OutOfMemory
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+
+    /// Waits until `cond` holds, polling it for up to 10 seconds.
+    async fn wait_for<F: Future<Output = bool>>(mut cond: impl FnMut() -> F) {
+        let poll = async {
+            while !cond().await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), poll)
+            .await
+            .expect("condition is not met in time");
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let mut registry = Registry::default();
+        registry.register("echo", |_: &Settings| {
+            Service::new(tower::service_fn(|mut conn: Connection| async move {
+                let (mut r, mut w) = io::split(&mut conn.io);
+                io::copy(&mut r, &mut w).await?;
+                Ok(())
+            }))
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel();
+        let server = Server::builder()
+            .registry(registry)
+            .listen(listener, "echo")
+            .shutdown(rx.map(|_| ()))
+            .build()
+            .unwrap();
+        let server = tokio::spawn(server.run());
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_provider() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        assert!(Server::builder().listen(listener, "none").build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let echo = |prefix: &'static [u8]| {
+            Service::new(tower::service_fn(move |mut conn: Connection| async move {
+                conn.io.write_all(prefix).await?;
+                Ok(())
+            }))
+        };
+
+        let listener: Arc<dyn Listener> = Arc::new(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let addr = listener.local_addr().unwrap();
+        let (mut tx, rx) = futures::channel::mpsc::channel(1);
+        let server = Server::builder()
+            .listen_service(listener.clone(), echo(b"old"))
+            .reload(rx)
+            .build()
+            .unwrap();
+        tokio::spawn(server.run());
+
+        let read = || async {
+            let mut stream = TcpStream::connect(&addr).await.unwrap();
+            let mut buf = vec![];
+            stream.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        assert_eq!(read().await, b"old");
+
+        // connections accepted before the reload are still served by the old service
+        tx.send(vec![(listener, echo(b"new"))]).await.unwrap();
+        wait_for(|| async { read().await == b"new" }).await;
+
+        tx.send(vec![]).await.unwrap();
+        wait_for(|| async { TcpStream::connect(&addr).await.is_err() }).await;
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let pong = Service::new(tower::service_fn(|mut conn: Connection| async move {
+            conn.io.read_u8().await?;
+            conn.io.write_all(b"pong").await?;
+            conn.io.read_u8().await?;
+            Ok(())
+        }));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (force_tx, force_rx) = oneshot::channel();
+        let server = Server::builder()
+            .listen_service(listener, pong)
+            .shutdown(shutdown_rx.map(|_| ()))
+            .force_shutdown(force_rx.map(|_| ()))
+            .drain_timeout(std::time::Duration::from_secs(60))
+            .build()
+            .unwrap();
+        let metrics = server.metrics();
+        let server = tokio::spawn(server.run());
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        wait_for(|| async { metrics.sessions() == 1 }).await;
+        shutdown_tx.send(()).unwrap();
+        wait_for(|| async { TcpStream::connect(addr).await.is_err() }).await;
+
+        stream.write_u8(0).await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+        assert!(!server.is_finished());
+
+        force_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(metrics.sessions(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_accept_errors() {
+        struct Failing(std::sync::Mutex<Vec<io::Error>>);
+
+        impl Listener for Failing {
+            fn accept(&self) -> BoxFuture<'_, io::Result<Connection>> {
+                match self.0.lock().unwrap().pop() {
+                    Some(e) => future::err(e).boxed(),
+                    None => future::pending().boxed(),
+                }
+            }
+
+            fn local_addr(&self) -> io::Result<String> {
+                Ok("failing".to_string())
+            }
+        }
+
+        let listener = Failing(std::sync::Mutex::new(vec![
+            io::Error::from(io::ErrorKind::ConnectionAborted),
+            io::Error::from(io::ErrorKind::OutOfMemory),
+            io::Error::from(io::ErrorKind::OutOfMemory),
+            io::Error::from(io::ErrorKind::ConnectionReset),
+        ]));
+        let server = Server::builder().listen(listener, "socks").build().unwrap();
+        let metrics = server.metrics();
+        tokio::spawn(server.run());
+
+        wait_for(|| async { metrics.accept_errors() == 4 }).await;
+        assert_eq!(metrics.accepted(), 0);
+        assert_eq!(metrics.accept_errors(), 4);
+        assert_eq!(metrics.accept_exhaustions(), 2);
+
+        let listener = Failing(std::sync::Mutex::new(vec![io::Error::other("closed")]));
+        let server = Server::builder().listen(listener, "socks").build().unwrap();
+        assert!(server.run().await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix() {
+        let path = std::env::temp_dir().join(format!("juno-server-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        assert_eq!(
+            Listener::local_addr(&listener).unwrap(),
+            format!("unix:{}", path.display())
+        );
+
+        let echo = Service::new(tower::service_fn(|mut conn: Connection| async move {
+            assert!(conn.meta.peer_addr.is_none());
+            assert_eq!(
+                conn.meta.peer_cred.map(|c| c.pid),
+                Some(Some(std::process::id() as i32))
+            );
+            conn.io.write_all(b"pong").await?;
+            Ok(())
+        }));
+        let server = Server::builder()
+            .listen_service(listener, echo)
+            .build()
+            .unwrap();
+        tokio::spawn(server.run());
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"pong");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
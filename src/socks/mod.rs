@@ -1,13 +1,16 @@
-pub(super) mod provider;
-mod v4;
-mod v5;
+//! SOCKS protocol messages, encoded and decoded on both the client and the server side.
+//!
+//! Every message is written in full on the wire, including the version number.
 
+pub(crate) mod provider;
+pub mod v4;
+pub mod v5;
+
+use bytes::{Buf, BufMut, BytesMut};
 use std::net::{SocketAddrV4, SocketAddrV6};
-use std::sync::Arc;
 use thiserror::Error;
 use tokio::io;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -21,25 +24,234 @@ pub enum Error {
     Io(#[from] io::Error),
 }
 
-type Result<T, E = Error> = std::result::Result<T, E>;
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Message decoded from bytes.
+pub trait Decode: Sized {
+    /// Decodes a message from the front of `buf`.
+    ///
+    /// Returns [`Error::NeedMoreData`] if `buf` holds only a part of the message.
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self>;
+}
+
+/// Message encoded into bytes.
+pub trait Encode {
+    fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()>;
+}
+
+/// Reads a message from `stream`, buffering the data in `buf`.
+///
+/// Data following the message is left in `buf`, to be passed to the next read.
+pub async fn read<T, S>(stream: &mut S, buf: &mut BytesMut) -> Result<T>
+where
+    T: Decode,
+    S: AsyncRead + Unpin,
+{
+    loop {
+        let mut view = &buf[..];
+        match T::decode(&mut view) {
+            Ok(msg) => {
+                let len = buf.len() - view.len();
+                buf.advance(len);
+                break Ok(msg);
+            }
+            Err(Error::NeedMoreData) => {}
+            Err(e) => break Err(e),
+        }
+
+        if stream.read_buf(buf).await? == 0 {
+            break Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+    }
+}
 
+/// Writes a message to `stream`.
+pub async fn write<T, S>(stream: &mut S, msg: &T) -> Result<()>
+where
+    T: Encode,
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(64);
+    msg.encode(&mut buf)?;
+    stream.write_all_buf(&mut buf).await?;
+    Ok(())
+}
+
+/// Address in a SOCKS message.
 #[derive(Debug, Clone, PartialEq, Eq)]
-enum SocketAddr {
+pub enum SocketAddr {
     V4(SocketAddrV4),
     V6(SocketAddrV6),
     Raw(String, u16),
 }
 
 impl SocketAddr {
-    fn v4(ip: u32, port: u16) -> Self {
+    pub fn v4(ip: u32, port: u16) -> Self {
         Self::V4(SocketAddrV4::new(ip.into(), port))
     }
 
-    fn v6(ip: u128, port: u16) -> Self {
+    pub fn v6(ip: u128, port: u16) -> Self {
         Self::V6(SocketAddrV6::new(ip.into(), port, 0, 0))
     }
 
-    fn raw(domain: String, port: u16) -> Self {
+    pub fn raw(domain: String, port: u16) -> Self {
         Self::Raw(domain, port)
     }
+
+    /// Creates `0.0.0.0:0`, sent where the address is not meaningful.
+    pub fn unspecified() -> Self {
+        Self::v4(0, 0)
+    }
+
+    /// Decodes an address of the SOCKS5 format, starting with the address type.
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        if buf.remaining() < 1 {
+            return Err(Error::NeedMoreData);
+        }
+
+        match buf.get_u8() {
+            1 => {
+                if buf.remaining() < 6 {
+                    return Err(Error::NeedMoreData);
+                }
+                Ok(Self::v4(buf.get_u32(), buf.get_u16()))
+            }
+            4 => {
+                if buf.remaining() < 18 {
+                    return Err(Error::NeedMoreData);
+                }
+                Ok(Self::v6(buf.get_u128(), buf.get_u16()))
+            }
+            3 => {
+                let domain = get_string(buf)?;
+                if buf.remaining() < 2 {
+                    return Err(Error::NeedMoreData);
+                }
+                Ok(Self::raw(domain, buf.get_u16()))
+            }
+            a_type => Err(Error::Protocol(format!("illegal address type `{a_type}`"))),
+        }
+    }
+
+    /// Encodes the address in the SOCKS5 format, starting with the address type.
+    pub fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        match self {
+            Self::V4(addr) => {
+                buf.put_u8(1);
+                buf.put_slice(&addr.ip().octets());
+                buf.put_u16(addr.port());
+            }
+            Self::V6(addr) => {
+                buf.put_u8(4);
+                buf.put_slice(&addr.ip().octets());
+                buf.put_u16(addr.port());
+            }
+            Self::Raw(domain, port) => {
+                buf.put_u8(3);
+                put_string(buf, domain)?;
+                buf.put_u16(*port);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl From<SocketAddr> for crate::Address {
+    fn from(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(addr) => Self::Ip(addr.into()),
+            SocketAddr::V6(addr) => Self::Ip(addr.into()),
+            SocketAddr::Raw(domain, port) => Self::Domain(domain, port),
+        }
+    }
+}
+
+impl From<crate::Address> for SocketAddr {
+    fn from(addr: crate::Address) -> Self {
+        match addr {
+            crate::Address::Ip(std::net::SocketAddr::V4(addr)) => Self::V4(addr),
+            crate::Address::Ip(std::net::SocketAddr::V6(addr)) => Self::V6(addr),
+            crate::Address::Domain(domain, port) => Self::Raw(domain, port),
+        }
+    }
+}
+
+/// Decodes a string prefixed with its length in an octet.
+fn get_string<B: Buf>(buf: &mut B) -> Result<String> {
+    if buf.remaining() < 1 {
+        return Err(Error::NeedMoreData);
+    }
+
+    let len = buf.get_u8() as usize;
+    if buf.remaining() < len {
+        return Err(Error::NeedMoreData);
+    }
+
+    let mut vec = vec![0; len];
+    buf.copy_to_slice(&mut vec);
+    String::from_utf8(vec).map_err(|e| Error::Protocol(e.to_string()))
+}
+
+/// Encodes a string prefixed with its length in an octet.
+fn put_string<B: BufMut>(buf: &mut B, s: &str) -> Result<()> {
+    let len = u8::try_from(s.len())
+        .map_err(|_| Error::Protocol(format!("`{s}` is longer than 255 octets")))?;
+    buf.put_u8(len);
+    buf.put_slice(s.as_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_read() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[5, 1, 0, 0x70]).await.unwrap();
+
+        let mut buf = BytesMut::new();
+        let greeting: v5::Greeting = read(&mut server, &mut buf).await.unwrap();
+        assert_eq!(greeting.methods, vec![v5::Method::NO_AUTH]);
+        assert_eq!(&buf[..], &[0x70]);
+
+        drop(client);
+        assert!(matches!(
+            read::<v5::Greeting, _>(&mut server, &mut buf).await,
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_socket_addr() {
+        for addr in [
+            SocketAddr::v4(0x7f000001, 80),
+            SocketAddr::v6(1, 443),
+            SocketAddr::raw("example.com".to_string(), 8080),
+        ] {
+            let mut buf = BytesMut::new();
+            addr.encode(&mut buf).unwrap();
+            assert_eq!(SocketAddr::decode(&mut buf.freeze()).unwrap(), addr);
+        }
+
+        let mut buf = Bytes::from_static(&[3, 4, 0x68, 0x6f, 0x67, 0x65, 0]);
+        assert!(matches!(
+            SocketAddr::decode(&mut buf),
+            Err(Error::NeedMoreData)
+        ));
+
+        let addr = SocketAddr::raw("a".repeat(256), 80);
+        assert!(addr.encode(&mut BytesMut::new()).is_err());
+    }
 }
@@ -1,27 +1,47 @@
 use super::*;
-use crate::Dialer;
+use crate::connector::Io;
+use crate::limit::REJECT_TIMEOUT;
+use crate::route::Context;
+use crate::{Auth, ClientLimit, Connection, Connector};
 use anyhow::anyhow;
+use bytes::BytesMut;
 use future::BoxFuture;
 use futures::prelude::*;
 use std::sync::Arc;
 use std::task;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
 
 #[derive(Clone)]
 pub struct Service {
-    dialer: Arc<Dialer>,
+    connector: Arc<dyn Connector>,
+    auth: Option<Arc<Auth>>,
+    client_limit: Option<Arc<ClientLimit>>,
 }
 
 impl Service {
-    pub fn new(dialer: Dialer) -> Self {
+    pub fn new(connector: Arc<dyn Connector>) -> Self {
         Self {
-            dialer: Arc::new(dialer),
+            connector,
+            auth: None,
+            client_limit: None,
         }
     }
+
+    /// Requires clients to authenticate with the username/password method of SOCKS5.
+    ///
+    /// SOCKS4 requests are rejected, as they carry no password.
+    pub fn auth(mut self, auth: Option<Arc<Auth>>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Replies with a general failure to clients over `limit`.
+    pub fn client_limit(mut self, limit: Option<Arc<ClientLimit>>) -> Self {
+        self.client_limit = limit;
+        self
+    }
 }
 
-impl tower::Service<TcpStream> for Service {
+impl<S: Io> tower::Service<Connection<S>> for Service {
     type Response = ();
     type Error = anyhow::Error;
     type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
@@ -30,16 +50,279 @@ impl tower::Service<TcpStream> for Service {
         task::Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, mut stream: TcpStream) -> Self::Future {
-        let dialer = Arc::clone(&self.dialer);
+    fn call(&mut self, conn: Connection<S>) -> Self::Future {
+        let connector = Arc::clone(&self.connector);
+        let auth = self.auth.clone();
+        let permit = self.client_limit.as_ref().map(|l| l.acquire(&conn.meta));
+        let ctx = Context::new("socks", &conn.meta);
+        let mut stream = conn.io;
+        if let Some(None) = permit {
+            return async move { tokio::time::timeout(REJECT_TIMEOUT, reject(stream)).await? }
+                .boxed();
+        }
 
         async move {
-            match stream.read_u8().await? {
-                4 => v4::handle_request(stream, dialer).err_into().await,
-                5 => v5::handle_request(stream, dialer).err_into().await,
+            let _permit = permit;
+            let mut buf = BytesMut::with_capacity(256);
+            if stream.read_buf(&mut buf).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+
+            match buf[0] {
+                4 => {
+                    v4::handle_request(stream, buf, connector, auth, ctx)
+                        .err_into()
+                        .await
+                }
+                5 => {
+                    v5::handle_request(stream, buf, connector, auth, ctx)
+                        .err_into()
+                        .await
+                }
                 ver => Err(anyhow!("illegal protocol version `{ver}`")),
             }
         }
         .boxed()
     }
 }
+
+/// Rejects the request of a client over the limit, in the version of the protocol it speaks.
+async fn reject<S: Io>(mut stream: S) -> anyhow::Result<()> {
+    let mut buf = BytesMut::with_capacity(256);
+    if stream.read_buf(&mut buf).await? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    match buf[0] {
+        4 => v4::reject(stream, buf).err_into().await,
+        5 => v5::reject(stream, buf).err_into().await,
+        ver => Err(anyhow!("illegal protocol version `{ver}`")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::Duplex;
+    use crate::inbound::Metadata;
+    use std::num::NonZeroUsize;
+    use tower::Service as _;
+
+    #[tokio::test]
+    async fn test_v4() {
+        let (connector, mut server) = Duplex::new();
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let conn = Connection::new(stream, Metadata::default());
+        tokio::spawn(Service::new(Arc::new(connector)).call(conn));
+
+        client
+            .write_all(&[4, 1, 0, 80, 0, 0, 0, 1, 0, 0x68, 0x6f, 0x67, 0x65, 0])
+            .await
+            .unwrap();
+        let mut buf = [0; 8];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 90, 0, 0, 0, 0, 0, 0]);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_v5() {
+        let (connector, mut server) = Duplex::new();
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let conn = Connection::new(stream, Metadata::default());
+        tokio::spawn(Service::new(Arc::new(connector)).call(conn));
+
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 0]);
+
+        client
+            .write_all(&[5, 1, 0, 3, 4, 0x68, 0x6f, 0x67, 0x65, 0, 80])
+            .await
+            .unwrap();
+        let mut buf = [0; 10];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_v5_auth() {
+        let (connector, mut server) = Duplex::new();
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let conn = Connection::new(stream, Metadata::default());
+        let auth = Auth::default().user("alice", "secret");
+        let service = Service::new(Arc::new(connector)).auth(Some(Arc::new(auth)));
+        tokio::spawn(service.clone().call(conn));
+
+        client.write_all(&[5, 2, 0, 2]).await.unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 2]);
+
+        client
+            .write_all(&[1, 5, b'a', b'l', b'i', b'c', b'e', 6])
+            .await
+            .unwrap();
+        client.write_all(b"secret").await.unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 0]);
+
+        client
+            .write_all(&[5, 1, 0, 3, 4, 0x68, 0x6f, 0x67, 0x65, 0, 80])
+            .await
+            .unwrap();
+        let mut buf = [0; 10];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_v5_auth_failure() {
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let conn = Connection::new(stream, Metadata::default());
+        let auth = Auth::default().user("alice", "secret");
+        let service = Service::new(Arc::new(Duplex::new().0)).auth(Some(Arc::new(auth)));
+        tokio::spawn(service.clone().call(conn));
+
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 0xFF]);
+    }
+
+    #[tokio::test]
+    async fn test_client_limit() {
+        let meta = Metadata {
+            peer_addr: Some("192.0.2.1:1000".parse().unwrap()),
+            ..Default::default()
+        };
+        let limit = Arc::new(ClientLimit::new(NonZeroUsize::new(1).unwrap()));
+        let _permit = limit.acquire(&meta).unwrap();
+
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let conn = Connection::new(stream, meta.clone());
+        let service = Service::new(Arc::new(Duplex::new().0)).client_limit(Some(limit));
+        tokio::spawn(service.clone().call(conn));
+
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 0]);
+
+        client
+            .write_all(&[5, 1, 0, 3, 4, 0x68, 0x6f, 0x67, 0x65, 0, 80])
+            .await
+            .unwrap();
+        let mut buf = [0; 10];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        // fails the request of a client only offering username/password as well
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let conn = Connection::new(stream, meta);
+        tokio::spawn(service.clone().call(conn));
+
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 2]);
+
+        client
+            .write_all(&[1, 5, b'a', b'l', b'i', b'c', b'e', 1, b'x'])
+            .await
+            .unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 0]);
+
+        client
+            .write_all(&[5, 1, 0, 3, 4, 0x68, 0x6f, 0x67, 0x65, 0, 80])
+            .await
+            .unwrap();
+        let mut buf = [0; 10];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_client_limit_session() {
+        let meta = Metadata {
+            peer_addr: Some("192.0.2.1:1000".parse().unwrap()),
+            ..Default::default()
+        };
+        let limit = Arc::new(ClientLimit::new(NonZeroUsize::new(1).unwrap()));
+        let (connector, mut server) = Duplex::new();
+        let service = Service::new(Arc::new(connector)).client_limit(Some(limit));
+
+        // a running session holds the only slot of the client
+        let (mut first, stream) = tokio::io::duplex(1024);
+        tokio::spawn(service.clone().call(Connection::new(stream, meta.clone())));
+
+        first.write_all(&[5, 1, 0]).await.unwrap();
+        let mut buf = [0; 2];
+        first.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 0]);
+
+        first
+            .write_all(&[5, 1, 0, 3, 4, 0x68, 0x6f, 0x67, 0x65, 0, 80])
+            .await
+            .unwrap();
+        let mut buf = [0; 10];
+        first.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        first.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let (mut second, stream) = tokio::io::duplex(1024);
+        tokio::spawn(service.clone().call(Connection::new(stream, meta)));
+
+        second.write_all(&[5, 1, 0]).await.unwrap();
+        let mut buf = [0; 2];
+        second.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 0]);
+
+        second
+            .write_all(&[5, 1, 0, 3, 4, 0x68, 0x6f, 0x67, 0x65, 0, 80])
+            .await
+            .unwrap();
+        let mut buf = [0; 10];
+        second.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_limit_timeout() {
+        let meta = Metadata {
+            peer_addr: Some("192.0.2.1:1000".parse().unwrap()),
+            ..Default::default()
+        };
+        let limit = Arc::new(ClientLimit::new(NonZeroUsize::new(1).unwrap()));
+        let _permit = limit.acquire(&meta).unwrap();
+
+        // closes the connection of a client over the limit sending nothing
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let conn = Connection::new(stream, meta);
+        let mut service = Service::new(Arc::new(Duplex::new().0)).client_limit(Some(limit));
+        assert!(service.call(conn).await.is_err());
+        assert_eq!(client.read(&mut [0]).await.unwrap(), 0);
+    }
+}
@@ -1,21 +1,31 @@
+//! SOCKS4 and SOCKS4a messages.
+
 use super::*;
-use crate::Dialer;
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crate::connector::Io;
+use crate::route::Context;
+use crate::{Auth, Connector};
+use bytes::{Buf, BufMut, BytesMut};
 use std::io::BufRead;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-enum Request {
+pub enum Request {
+    /// Connects to the address, on behalf of the user ID.
     Connect(SocketAddr, String),
     Bind(SocketAddr, String),
 }
 
-impl Request {
-    fn from_buf<B: Buf>(buf: &mut B) -> Result<Self> {
-        if buf.remaining() < 8 {
+impl Decode for Request {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        if buf.remaining() < 9 {
             return Err(Error::NeedMoreData);
         }
 
+        let ver = buf.get_u8();
+        if ver != 4 {
+            return Err(Error::Protocol(format!("illegal version number `{ver}`")));
+        }
+
         let code = match buf.get_u8() {
             code @ (1 | 2) => code,
             code => return Err(Error::Protocol(format!("illegal request `{code}`"))),
@@ -23,10 +33,10 @@ impl Request {
 
         let port = buf.get_u16();
         let ip = buf.get_u32();
-        let user = Self::read_string(buf)?;
+        let user = get_string(buf)?;
 
         let addr = if (1..0x100).contains(&ip) {
-            SocketAddr::raw(Self::read_string(buf)?, port)
+            SocketAddr::raw(get_string(buf)?, port)
         } else {
             SocketAddr::v4(ip, port)
         };
@@ -37,87 +47,169 @@ impl Request {
             _ => unreachable!(),
         }
     }
+}
 
-    fn read_string<B: Buf>(buf: &mut B) -> Result<String> {
-        let mut vec = vec![];
-        let len = buf.reader().read_until(0, &mut vec)?;
-        if vec[len - 1] != 0 {
-            return Err(Error::NeedMoreData);
+impl Encode for Request {
+    /// Encodes the request, in the SOCKS4a format if the address is a domain name.
+    fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        let (code, addr, user) = match self {
+            Request::Connect(addr, user) => (1, addr, user),
+            Request::Bind(addr, user) => (2, addr, user),
+        };
+
+        buf.put_u8(4);
+        buf.put_u8(code);
+        match addr {
+            SocketAddr::V4(addr) => {
+                buf.put_u16(addr.port());
+                buf.put_slice(&addr.ip().octets());
+                put_string(buf, user)?;
+            }
+            SocketAddr::Raw(domain, port) => {
+                buf.put_u16(*port);
+                buf.put_u32(1);
+                put_string(buf, user)?;
+                put_string(buf, domain)?;
+            }
+            SocketAddr::V6(_) => {
+                return Err(Error::Protocol("IPv6 is not supported".to_string()));
+            }
         }
 
-        vec.pop();
-        String::from_utf8(vec).map_err(|e| Error::Protocol(e.to_string()))
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum Response {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
     Granted,
     Rejected,
+    IdentdUnreachable,
+    IdentdMismatch,
 }
 
-impl From<Response> for Bytes {
-    fn from(res: Response) -> Self {
-        let mut buf = BytesMut::with_capacity(8);
+impl Status {
+    pub fn code(self) -> u8 {
+        match self {
+            Status::Granted => 90,
+            Status::Rejected => 91,
+            Status::IdentdUnreachable => 92,
+            Status::IdentdMismatch => 93,
+        }
+    }
+}
 
-        buf.put_u8(0);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: Status,
+
+    /// Address bound by the server, meaningful only in replies to [`Request::Bind`].
+    pub addr: SocketAddrV4,
+}
 
-        match res {
-            Response::Granted => buf.put_u8(90),
-            Response::Rejected => buf.put_u8(91),
+impl Response {
+    pub fn new(status: Status) -> Self {
+        Self {
+            status,
+            addr: SocketAddrV4::new(0.into(), 0),
         }
+    }
+}
 
-        buf.put_u16(0);
-        buf.put_u32(0);
+impl Decode for Response {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        if buf.remaining() < 8 {
+            return Err(Error::NeedMoreData);
+        }
 
-        buf.freeze()
+        let ver = buf.get_u8();
+        if ver != 0 {
+            return Err(Error::Protocol(format!("illegal version number `{ver}`")));
+        }
+
+        let status = match buf.get_u8() {
+            90 => Status::Granted,
+            91 => Status::Rejected,
+            92 => Status::IdentdUnreachable,
+            93 => Status::IdentdMismatch,
+            code => return Err(Error::Protocol(format!("illegal reply `{code}`"))),
+        };
+
+        let port = buf.get_u16();
+        let addr = SocketAddrV4::new(buf.get_u32().into(), port);
+
+        Ok(Self { status, addr })
     }
 }
 
-pub async fn handle_request(mut client: TcpStream, dialer: Arc<Dialer>) -> Result<()> {
-    let request = read_request(&mut client).await?;
+impl Encode for Response {
+    fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        buf.put_u8(0);
+        buf.put_u8(self.status.code());
+        buf.put_u16(self.addr.port());
+        buf.put_slice(&self.addr.ip().octets());
 
-    let (server, response) = match request {
+        Ok(())
+    }
+}
+
+/// Decodes a NUL-terminated string.
+fn get_string<B: Buf>(buf: &mut B) -> Result<String> {
+    let mut vec = vec![];
+    buf.reader().read_until(0, &mut vec)?;
+    if vec.pop() != Some(0) {
+        return Err(Error::NeedMoreData);
+    }
+
+    String::from_utf8(vec).map_err(|e| Error::Protocol(e.to_string()))
+}
+
+/// Encodes a NUL-terminated string.
+fn put_string<B: BufMut>(buf: &mut B, s: &str) -> Result<()> {
+    if s.contains('\0') {
+        return Err(Error::Protocol(format!("`{s}` contains NUL")));
+    }
+
+    buf.put_slice(s.as_bytes());
+    buf.put_u8(0);
+    Ok(())
+}
+
+pub(super) async fn handle_request<S: Io>(
+    mut client: S,
+    mut buf: BytesMut,
+    connector: Arc<dyn Connector>,
+    auth: Option<Arc<Auth>>,
+    ctx: Context,
+) -> Result<()> {
+    let request = read(&mut client, &mut buf).await?;
+
+    let (server, status) = match request {
+        Request::Connect(..) if auth.is_some() => (None, Status::Rejected),
         Request::Connect(addr, _) => {
-            let res = match addr {
-                SocketAddr::V4(addr) => dialer.dial(addr).await,
-                SocketAddr::Raw(domain, port) => dialer.dial((domain, port)).await,
-                _ => unreachable!(),
-            };
-
-            if let Ok(server) = res {
-                (Some(server), Response::Granted)
+            if let Ok(server) = connector.connect(&ctx, &addr.into()).await {
+                (Some(server), Status::Granted)
             } else {
-                (None, Response::Rejected)
+                (None, Status::Rejected)
             }
         }
-        Request::Bind(_, _) => (None, Response::Rejected),
+        Request::Bind(_, _) => (None, Status::Rejected),
     };
 
-    let mut buf: Bytes = response.into();
-    client.write_all_buf(&mut buf).await?;
+    write(&mut client, &Response::new(status)).await?;
 
     if let Some(mut server) = server {
+        server.write_all_buf(&mut buf).await?;
         io::copy_bidirectional(&mut client, &mut server).await?;
     }
 
     Ok(())
 }
 
-async fn read_request(stream: &mut TcpStream) -> Result<Request> {
-    let mut buf = BytesMut::with_capacity(256);
-    loop {
-        if stream.read_buf(&mut buf).await? == 0 {
-            break Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
-        }
-
-        let mut view = Bytes::copy_from_slice(&buf);
-        match Request::from_buf(&mut view) {
-            Ok(req) => break Ok(req),
-            Err(Error::NeedMoreData) => {}
-            Err(e) => break Err(e),
-        }
-    }
+/// Rejects the request, as the server cannot serve it.
+pub(super) async fn reject<S: Io>(mut client: S, mut buf: BytesMut) -> Result<()> {
+    let _: Request = read(&mut client, &mut buf).await?;
+    write(&mut client, &Response::new(Status::Rejected)).await
 }
 
 #[cfg(test)]
@@ -126,10 +218,10 @@ mod tests {
     use bytes::Bytes;
 
     #[test]
-    fn test_request_from_buf() {
+    fn test_request_decode() {
         {
-            let mut buf = Bytes::from_static(&[1, 1, 2, 0, 0, 0, 0, 0x68, 0x6f, 0x67, 0x65, 0]);
-            let req = Request::from_buf(&mut buf).unwrap();
+            let mut buf = Bytes::from_static(&[4, 1, 1, 2, 0, 0, 0, 0, 0x68, 0x6f, 0x67, 0x65, 0]);
+            let req = Request::decode(&mut buf).unwrap();
             assert_eq!(
                 req,
                 Request::Connect(SocketAddr::v4(0, 0x0102), "hoge".to_string())
@@ -137,35 +229,65 @@ mod tests {
         }
         {
             let mut buf =
-                Bytes::from_static(&[2, 1, 2, 0, 0, 0, 255, 0, 0x68, 0x6f, 0x67, 0x65, 0]);
-            let req = Request::from_buf(&mut buf).unwrap();
+                Bytes::from_static(&[4, 2, 1, 2, 0, 0, 0, 255, 0, 0x68, 0x6f, 0x67, 0x65, 0]);
+            let req = Request::decode(&mut buf).unwrap();
             assert_eq!(
                 req,
                 Request::Bind(SocketAddr::raw("hoge".to_string(), 0x0102), "".to_string())
             );
         }
         {
-            let mut buf = Bytes::from_static(&[1, 2, 3, 4, 5, 6, 7]);
+            let mut buf = Bytes::from_static(&[4, 1, 2, 3, 4, 5, 6, 7]);
             assert!(matches!(
-                Request::from_buf(&mut buf),
+                Request::decode(&mut buf),
                 Err(Error::NeedMoreData)
             ));
         }
         {
-            let mut buf = Bytes::from_static(&[0, 1, 2, 3, 4, 5, 6, 7]);
-            assert!(matches!(
-                Request::from_buf(&mut buf),
-                Err(Error::Protocol(_))
-            ));
+            let mut buf = Bytes::from_static(&[4, 0, 1, 2, 3, 4, 5, 6, 7]);
+            assert!(matches!(Request::decode(&mut buf), Err(Error::Protocol(_))));
+        }
+        {
+            let mut buf = Bytes::from_static(&[5, 1, 1, 2, 3, 4, 5, 6, 0]);
+            assert!(matches!(Request::decode(&mut buf), Err(Error::Protocol(_))));
         }
     }
 
     #[test]
-    fn test_read_string() {
+    fn test_request_encode() {
+        for req in [
+            Request::Connect(SocketAddr::v4(0x7f000001, 80), "hoge".to_string()),
+            Request::Bind(
+                SocketAddr::raw("example.com".to_string(), 80),
+                "".to_string(),
+            ),
+        ] {
+            let mut buf = BytesMut::new();
+            req.encode(&mut buf).unwrap();
+            assert_eq!(Request::decode(&mut buf.freeze()).unwrap(), req);
+        }
+
+        let req = Request::Connect(SocketAddr::v6(1, 80), "".to_string());
+        assert!(req.encode(&mut BytesMut::new()).is_err());
+    }
+
+    #[test]
+    fn test_response() {
+        let mut buf = BytesMut::new();
+        Response::new(Status::Granted).encode(&mut buf).unwrap();
+        assert_eq!(&buf[..], &[0, 90, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            Response::decode(&mut buf.freeze()).unwrap(),
+            Response::new(Status::Granted)
+        );
+    }
+
+    #[test]
+    fn test_get_string() {
         let mut buf = Bytes::from_static(b"a");
-        assert!(matches!(
-            Request::read_string(&mut buf),
-            Err(Error::NeedMoreData)
-        ));
+        assert!(matches!(get_string(&mut buf), Err(Error::NeedMoreData)));
+
+        let mut buf = Bytes::new();
+        assert!(matches!(get_string(&mut buf), Err(Error::NeedMoreData)));
     }
 }
@@ -1,66 +1,167 @@
+//! SOCKS5 messages, defined in RFC 1928 and RFC 1929.
+
 use super::*;
-use crate::Dialer;
-use bytes::{Buf, BufMut, Bytes, BytesMut};
-use std::io::Read;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use crate::connector::Io;
+use crate::route::Context;
+use crate::{Auth, Connector, DialError};
+use bytes::{Buf, BufMut, BytesMut};
+use std::sync::Arc;
+
+/// Authentication method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Method(pub u8);
+
+impl Method {
+    pub const NO_AUTH: Self = Self(0x00);
+    pub const GSSAPI: Self = Self(0x01);
+    pub const USERNAME_PASSWORD: Self = Self(0x02);
+    pub const NO_ACCEPTABLE: Self = Self(0xFF);
+}
+
+/// Authentication methods offered by the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Greeting {
+    pub methods: Vec<Method>,
+}
+
+impl Decode for Greeting {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        if buf.remaining() < 2 {
+            return Err(Error::NeedMoreData);
+        }
+
+        get_version(buf, 5)?;
+        let len = buf.get_u8() as usize;
+        if buf.remaining() < len {
+            return Err(Error::NeedMoreData);
+        }
+
+        let methods = (0..len).map(|_| Method(buf.get_u8())).collect();
+        Ok(Self { methods })
+    }
+}
+
+impl Encode for Greeting {
+    fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        let len = u8::try_from(self.methods.len())
+            .map_err(|_| Error::Protocol("too many methods".to_string()))?;
+
+        buf.put_u8(5);
+        buf.put_u8(len);
+        for method in &self.methods {
+            buf.put_u8(method.0);
+        }
+
+        Ok(())
+    }
+}
+
+/// Authentication method selected by the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodSelection {
+    pub method: Method,
+}
+
+impl Decode for MethodSelection {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        if buf.remaining() < 2 {
+            return Err(Error::NeedMoreData);
+        }
+
+        get_version(buf, 5)?;
+        Ok(Self {
+            method: Method(buf.get_u8()),
+        })
+    }
+}
+
+impl Encode for MethodSelection {
+    fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        buf.put_u8(5);
+        buf.put_u8(self.method.0);
+        Ok(())
+    }
+}
+
+/// Credentials of the username/password authentication.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthRequest {
+    pub username: String,
+    pub password: String,
+}
+
+impl Decode for AuthRequest {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        if buf.remaining() < 1 {
+            return Err(Error::NeedMoreData);
+        }
 
+        get_version(buf, 1)?;
+        let username = get_string(buf)?;
+        let password = get_string(buf)?;
+        Ok(Self { username, password })
+    }
+}
+
+impl Encode for AuthRequest {
+    fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        buf.put_u8(1);
+        put_string(buf, &self.username)?;
+        put_string(buf, &self.password)
+    }
+}
+
+/// Result of the username/password authentication.
 #[derive(Debug, Clone, PartialEq, Eq)]
-enum Request {
+pub struct AuthResponse {
+    pub success: bool,
+}
+
+impl Decode for AuthResponse {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        if buf.remaining() < 2 {
+            return Err(Error::NeedMoreData);
+        }
+
+        get_version(buf, 1)?;
+        Ok(Self {
+            success: buf.get_u8() == 0,
+        })
+    }
+}
+
+impl Encode for AuthResponse {
+    fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        buf.put_u8(1);
+        buf.put_u8(if self.success { 0 } else { 1 });
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
     Connect(SocketAddr),
     Bind(SocketAddr),
     UdpAssociate(SocketAddr),
 }
 
-impl Request {
-    fn from_buf<B: Buf>(buf: &mut B) -> Result<Self> {
-        if buf.remaining() < 2 {
+impl Decode for Request {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        if buf.remaining() < 3 {
             return Err(Error::NeedMoreData);
         }
 
+        get_version(buf, 5)?;
         let cmd = buf.get_u8();
         let rsv = buf.get_u8();
         if rsv != 0 {
             return Err(Error::Protocol("reserved octet is not 0".to_string()));
         }
-        if !matches!(cmd, 1 | 2 | 3) {
+        if !matches!(cmd, 1..=3) {
             return Err(Error::Protocol(format!("illegal request `{cmd}`")));
         }
 
-        if buf.remaining() < 1 {
-            return Err(Error::NeedMoreData);
-        }
-        let addr = match buf.get_u8() {
-            1 => {
-                if buf.remaining() < 6 {
-                    return Err(Error::NeedMoreData);
-                }
-                SocketAddr::v4(buf.get_u32(), buf.get_u16())
-            }
-            4 => {
-                if buf.remaining() < 18 {
-                    return Err(Error::NeedMoreData);
-                }
-                SocketAddr::v6(buf.get_u128(), buf.get_u16())
-            }
-            3 => {
-                if buf.remaining() < 1 {
-                    return Err(Error::NeedMoreData);
-                }
-
-                let len = buf.get_u8() as usize;
-                if buf.remaining() < len + 2 {
-                    return Err(Error::NeedMoreData);
-                }
-
-                let mut vec = vec![0; len];
-                buf.reader().read_exact(&mut vec)?;
-
-                let domain = String::from_utf8(vec).map_err(|e| Error::Protocol(e.to_string()))?;
-                SocketAddr::raw(domain, buf.get_u16())
-            }
-            a_type => return Err(Error::Protocol(format!("illegal address type `{a_type}`"))),
-        };
+        let addr = SocketAddr::decode(buf)?;
 
         match cmd {
             1 => Ok(Request::Connect(addr)),
@@ -71,95 +172,214 @@ impl Request {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum Response {
+impl Encode for Request {
+    fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        let (cmd, addr) = match self {
+            Request::Connect(addr) => (1, addr),
+            Request::Bind(addr) => (2, addr),
+            Request::UdpAssociate(addr) => (3, addr),
+        };
+
+        buf.put_u8(5);
+        buf.put_u8(cmd);
+        buf.put_u8(0);
+        addr.encode(buf)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
     Succeeded,
     Failed,
-    Unsupported,
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressNotSupported,
 }
 
-impl From<Response> for Bytes {
-    fn from(res: Response) -> Self {
-        let code = match res {
-            Response::Succeeded => 0,
-            Response::Failed => 1,
-            Response::Unsupported => 7,
+impl Reply {
+    pub fn code(self) -> u8 {
+        match self {
+            Reply::Succeeded => 0,
+            Reply::Failed => 1,
+            Reply::NotAllowed => 2,
+            Reply::NetworkUnreachable => 3,
+            Reply::HostUnreachable => 4,
+            Reply::ConnectionRefused => 5,
+            Reply::TtlExpired => 6,
+            Reply::CommandNotSupported => 7,
+            Reply::AddressNotSupported => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub reply: Reply,
+
+    /// Address bound by the server.
+    pub addr: SocketAddr,
+}
+
+impl Response {
+    pub fn new(reply: Reply) -> Self {
+        Self {
+            reply,
+            addr: SocketAddr::unspecified(),
+        }
+    }
+}
+
+impl Decode for Response {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        if buf.remaining() < 3 {
+            return Err(Error::NeedMoreData);
+        }
+
+        get_version(buf, 5)?;
+        let reply = match buf.get_u8() {
+            0 => Reply::Succeeded,
+            1 => Reply::Failed,
+            2 => Reply::NotAllowed,
+            3 => Reply::NetworkUnreachable,
+            4 => Reply::HostUnreachable,
+            5 => Reply::ConnectionRefused,
+            6 => Reply::TtlExpired,
+            7 => Reply::CommandNotSupported,
+            8 => Reply::AddressNotSupported,
+            code => return Err(Error::Protocol(format!("illegal reply `{code}`"))),
         };
+        buf.advance(1);
+        let addr = SocketAddr::decode(buf)?;
 
-        let mut buf = BytesMut::with_capacity(16);
+        Ok(Self { reply, addr })
+    }
+}
 
+impl Encode for Response {
+    fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
         buf.put_u8(5);
-        buf.put_u8(code);
+        buf.put_u8(self.reply.code());
         buf.put_u8(0);
-        buf.put_u8(1);
-        buf.put_u32(0);
+        self.addr.encode(buf)
+    }
+}
+
+/// Header of a UDP datagram relayed by the server, followed by the data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpHeader {
+    /// Fragment number, or 0 if the datagram is not fragmented.
+    pub frag: u8,
+    pub addr: SocketAddr,
+}
+
+impl Decode for UdpHeader {
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self> {
+        if buf.remaining() < 3 {
+            return Err(Error::NeedMoreData);
+        }
+
+        if buf.get_u16() != 0 {
+            return Err(Error::Protocol("reserved octets are not 0".to_string()));
+        }
+        let frag = buf.get_u8();
+        let addr = SocketAddr::decode(buf)?;
+
+        Ok(Self { frag, addr })
+    }
+}
+
+impl Encode for UdpHeader {
+    fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
         buf.put_u16(0);
+        buf.put_u8(self.frag);
+        self.addr.encode(buf)
+    }
+}
 
-        buf.freeze()
+fn get_version<B: Buf>(buf: &mut B, expected: u8) -> Result<()> {
+    match buf.get_u8() {
+        ver if ver == expected => Ok(()),
+        ver => Err(Error::Protocol(format!("illegal version number `{ver}`"))),
     }
 }
 
-pub async fn handle_request(mut client: TcpStream, dialer: Arc<Dialer>) -> Result<()> {
-    let auth_req = {
-        let len = client.read_u8().await? as usize;
-        let mut auth = vec![0; len];
-        client.read_exact(&mut auth).await?;
-        auth
+pub(super) async fn handle_request<S: Io>(
+    mut client: S,
+    mut buf: BytesMut,
+    connector: Arc<dyn Connector>,
+    auth: Option<Arc<Auth>>,
+    mut ctx: Context,
+) -> Result<()> {
+    let greeting: Greeting = read(&mut client, &mut buf).await?;
+    let required = match auth {
+        Some(_) => Method::USERNAME_PASSWORD,
+        None => Method::NO_AUTH,
     };
-    if auth_req.contains(&0x00) {
-        client.write_all(&[0x05, 0x00]).await?;
+    let method = if greeting.methods.contains(&required) {
+        required
     } else {
-        client.write_all(&[0x05, 0xFF]).await?;
+        Method::NO_ACCEPTABLE
+    };
+    write(&mut client, &MethodSelection { method }).await?;
+    if method == Method::NO_ACCEPTABLE {
         return Ok(());
     }
 
-    let request = read_request(&mut client).await?;
+    if let Some(auth) = auth {
+        let req: AuthRequest = read(&mut client, &mut buf).await?;
+        let success = auth.verify(&req.username, &req.password);
+        write(&mut client, &AuthResponse { success }).await?;
+        if !success {
+            return Ok(());
+        }
+        ctx.user = Some(req.username);
+    }
 
-    let (server, response) = match request {
-        Request::Connect(addr) => {
-            let res = match addr {
-                SocketAddr::V4(addr) => dialer.dial(addr).await,
-                SocketAddr::V6(addr) => dialer.dial(addr).await,
-                SocketAddr::Raw(domain, port) => dialer.dial((domain, port)).await,
-            };
+    let request = read(&mut client, &mut buf).await?;
 
-            match res {
-                Ok(server) => (Some(server), Response::Succeeded),
-                Err(_) => (None, Response::Failed),
-            }
-        }
-        Request::Bind(_) | Request::UdpAssociate(_) => (None, Response::Unsupported),
+    let (server, reply) = match request {
+        Request::Connect(addr) => match connector.connect(&ctx, &addr.into()).await {
+            Ok(server) => (Some(server), Reply::Succeeded),
+            Err(DialError::Rejected | DialError::Forbidden(_)) => (None, Reply::NotAllowed),
+            Err(DialError::TimedOut) => (None, Reply::TtlExpired),
+            Err(_) => (None, Reply::Failed),
+        },
+        Request::Bind(_) | Request::UdpAssociate(_) => (None, Reply::CommandNotSupported),
     };
 
-    let mut buf: Bytes = response.into();
-    client.write_all_buf(&mut buf).await?;
+    write(&mut client, &Response::new(reply)).await?;
 
     if let Some(mut server) = server {
+        server.write_all_buf(&mut buf).await?;
         io::copy_bidirectional(&mut client, &mut server).await?;
     }
 
     Ok(())
 }
 
-async fn read_request(client: &mut TcpStream) -> Result<Request> {
-    let ver = client.read_u8().await?;
-    if ver != 5 {
-        return Err(Error::Protocol(format!("illegal version number `{ver}`")));
+/// Replies the request with a general failure, as the server cannot serve it.
+pub(super) async fn reject<S: Io>(mut client: S, mut buf: BytesMut) -> Result<()> {
+    let greeting: Greeting = read(&mut client, &mut buf).await?;
+    let method = [Method::NO_AUTH, Method::USERNAME_PASSWORD]
+        .into_iter()
+        .find(|m| greeting.methods.contains(m))
+        .unwrap_or(Method::NO_ACCEPTABLE);
+    write(&mut client, &MethodSelection { method }).await?;
+    if method == Method::NO_ACCEPTABLE {
+        return Ok(());
     }
-
-    let mut buf = BytesMut::with_capacity(256);
-    loop {
-        if client.read_buf(&mut buf).await? == 0 {
-            break Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
-        }
-
-        let mut view = Bytes::copy_from_slice(&buf);
-        match Request::from_buf(&mut view) {
-            Ok(req) => break Ok(req),
-            Err(Error::NeedMoreData) => continue,
-            Err(e) => break Err(e),
-        }
+    if method == Method::USERNAME_PASSWORD {
+        // accepts any credentials to reply the failure to the request
+        let _: AuthRequest = read(&mut client, &mut buf).await?;
+        write(&mut client, &AuthResponse { success: true }).await?;
     }
+
+    let _: Request = read(&mut client, &mut buf).await?;
+    write(&mut client, &Response::new(Reply::Failed)).await
 }
 
 #[cfg(test)]
@@ -168,94 +388,131 @@ mod tests {
     use bytes::Bytes;
 
     #[test]
-    fn test_request_from_buf() {
+    fn test_request_decode() {
         {
-            let mut buf = Bytes::from_static(&[1, 0, 1, 2, 3, 4, 5, 6, 7]);
-            let req = Request::from_buf(&mut buf).unwrap();
+            let mut buf = Bytes::from_static(&[5, 1, 0, 1, 2, 3, 4, 5, 6, 7]);
+            let req = Request::decode(&mut buf).unwrap();
             assert_eq!(req, Request::Connect(SocketAddr::v4(0x02030405, 0x0607)));
         }
         {
             let mut buf = Bytes::from_static(&[
-                2, 0, 4, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B,
+                5, 2, 0, 4, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B,
                 0x0C, 0x0D, 0x0E, 0x0F, 0x10, 0x11,
             ]);
-            let req = Request::from_buf(&mut buf).unwrap();
+            let req = Request::decode(&mut buf).unwrap();
             assert_eq!(
                 req,
                 Request::Bind(SocketAddr::v6(0x000102030405060708090A0B0C0D0E0F, 0x1011))
             );
         }
         {
-            let mut buf = Bytes::from_static(&[3, 0, 3, 4, 0x68, 0x6f, 0x67, 0x65, 0x12, 0x34]);
-            let req = Request::from_buf(&mut buf).unwrap();
+            let mut buf = Bytes::from_static(&[5, 3, 0, 3, 4, 0x68, 0x6f, 0x67, 0x65, 0x12, 0x34]);
+            let req = Request::decode(&mut buf).unwrap();
             assert_eq!(
                 req,
                 Request::UdpAssociate(SocketAddr::raw("hoge".to_string(), 0x1234))
             );
         }
         {
-            let mut buf = Bytes::from_static(&[1]);
+            let mut buf = Bytes::from_static(&[5, 1]);
             assert!(matches!(
-                Request::from_buf(&mut buf),
+                Request::decode(&mut buf),
                 Err(Error::NeedMoreData)
             ));
         }
         {
-            let mut buf = Bytes::from_static(&[1, 2]);
-            assert!(matches!(
-                Request::from_buf(&mut buf),
-                Err(Error::Protocol(_))
-            ));
+            let mut buf = Bytes::from_static(&[5, 1, 2]);
+            assert!(matches!(Request::decode(&mut buf), Err(Error::Protocol(_))));
         }
         {
-            let mut buf = Bytes::from_static(&[0, 0]);
-            assert!(matches!(
-                Request::from_buf(&mut buf),
-                Err(Error::Protocol(_))
-            ));
+            let mut buf = Bytes::from_static(&[5, 0, 0]);
+            assert!(matches!(Request::decode(&mut buf), Err(Error::Protocol(_))));
         }
         {
-            let mut buf = Bytes::from_static(&[3, 0]);
+            let mut buf = Bytes::from_static(&[4, 1, 0]);
+            assert!(matches!(Request::decode(&mut buf), Err(Error::Protocol(_))));
+        }
+        {
+            let mut buf = Bytes::from_static(&[5, 3, 0]);
             assert!(matches!(
-                Request::from_buf(&mut buf),
+                Request::decode(&mut buf),
                 Err(Error::NeedMoreData)
             ));
         }
         {
-            let mut buf = Bytes::from_static(&[1, 0, 1, 0, 0, 0]);
+            let mut buf = Bytes::from_static(&[5, 1, 0, 1, 0, 0, 0]);
             assert!(matches!(
-                Request::from_buf(&mut buf),
+                Request::decode(&mut buf),
                 Err(Error::NeedMoreData)
             ));
         }
         {
             let mut buf =
-                Bytes::from_static(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+                Bytes::from_static(&[5, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
             assert!(matches!(
-                Request::from_buf(&mut buf),
+                Request::decode(&mut buf),
                 Err(Error::NeedMoreData)
             ));
         }
         {
-            let mut buf = Bytes::from_static(&[2, 0, 3]);
+            let mut buf = Bytes::from_static(&[5, 2, 0, 3]);
             assert!(matches!(
-                Request::from_buf(&mut buf),
+                Request::decode(&mut buf),
                 Err(Error::NeedMoreData)
             ));
         }
         {
-            let mut buf = Bytes::from_static(&[2, 0, 3, 4, 0, 0, 0]);
+            let mut buf = Bytes::from_static(&[5, 2, 0, 3, 4, 0, 0, 0]);
             assert!(matches!(
-                Request::from_buf(&mut buf),
+                Request::decode(&mut buf),
                 Err(Error::NeedMoreData)
             ));
         }
         {
-            let mut buf = Bytes::from_static(&[2, 0, 5]);
-            assert!(matches!(
-                Request::from_buf(&mut buf),
-                Err(Error::Protocol(_))
-            ));
+            let mut buf = Bytes::from_static(&[5, 2, 0, 5]);
+            assert!(matches!(Request::decode(&mut buf), Err(Error::Protocol(_))));
         }
     }
+
+    #[test]
+    fn test_round_trip() {
+        fn round_trip<T: Decode + Encode + PartialEq + std::fmt::Debug>(msg: T) {
+            let mut buf = BytesMut::new();
+            msg.encode(&mut buf).unwrap();
+            let mut buf = buf.freeze();
+            assert_eq!(T::decode(&mut buf).unwrap(), msg);
+            assert!(!buf.has_remaining());
+        }
+
+        round_trip(Greeting {
+            methods: vec![Method::NO_AUTH, Method::USERNAME_PASSWORD],
+        });
+        round_trip(MethodSelection {
+            method: Method::NO_ACCEPTABLE,
+        });
+        round_trip(AuthRequest {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+        });
+        round_trip(AuthResponse { success: false });
+        round_trip(Request::Connect(SocketAddr::raw(
+            "example.com".to_string(),
+            443,
+        )));
+        round_trip(Response {
+            reply: Reply::ConnectionRefused,
+            addr: SocketAddr::v6(1, 1080),
+        });
+        round_trip(UdpHeader {
+            frag: 0,
+            addr: SocketAddr::v4(0x7f000001, 53),
+        });
+    }
+
+    #[test]
+    fn test_response_encode() {
+        let mut buf = BytesMut::new();
+        Response::new(Reply::Succeeded).encode(&mut buf).unwrap();
+        assert_eq!(&buf[..], &[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
 }
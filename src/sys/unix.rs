@@ -1,37 +1,201 @@
-use anyhow::{Context as _, Result};
+use crate::Sockets;
+use anyhow::{anyhow, Context as _, Result};
+use futures::prelude::*;
+use juno::config::SocketConfig;
+use juno::server::Listener;
+use juno::Connection;
+use std::ffi::{CString, OsStr, OsString};
 use std::os::unix::prelude::*;
-use tokio::io;
-use tokio::net::TcpListener;
+use std::path::Path;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{self, AsyncReadExt};
+use tokio::net::unix::pipe;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::process::Child;
 
-pub async fn recv_signal() -> io::Result<()> {
+/// Returns a stream yielding on each request to shut down, which is SIGINT or SIGTERM.
+pub fn shutdown_signal() -> io::Result<impl Stream<Item = ()>> {
     use tokio::signal::unix::*;
 
     let mut interrupt = signal(SignalKind::interrupt())?;
     let mut terminate = signal(SignalKind::terminate())?;
+    Ok(stream::poll_fn(move |cx| {
+        // polls both not to miss waking up on either
+        match (interrupt.poll_recv(cx), terminate.poll_recv(cx)) {
+            (Poll::Pending, Poll::Pending) => Poll::Pending,
+            _ => Poll::Ready(Some(())),
+        }
+    }))
+}
+
+/// Returns a stream yielding on each request to reload the configuration, which is SIGHUP.
+pub fn reload_signal() -> io::Result<impl Stream<Item = ()>> {
+    use tokio::signal::unix::*;
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    Ok(stream::poll_fn(move |cx| hangup.poll_recv(cx)))
+}
+
+/// Returns a stream yielding on each request to upgrade to a new binary, which is SIGUSR2.
+pub fn upgrade_signal() -> io::Result<impl Stream<Item = ()>> {
+    use tokio::signal::unix::*;
+
+    let mut user_defined2 = signal(SignalKind::user_defined2())?;
+    Ok(stream::poll_fn(move |cx| user_defined2.poll_recv(cx)))
+}
+
+/// Environment variable telling a new process the sockets passed on upgrade, as lines of
+/// `<fd>=<address>`.
+const LISTENERS_ENV: &str = "JUNO_LISTENERS";
+
+/// Environment variable telling a new process the pipe to report its readiness through on
+/// upgrade.
+const READY_ENV: &str = "JUNO_READY_FD";
 
-    tokio::select! {
-        _ = interrupt.recv() => {},
-        _ = terminate.recv() => {},
+/// Time a new process is given to be ready on upgrade.
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Starts the binary this process was started as, with the same arguments, passing it `sockets`
+/// keyed by their addresses, and waits for it to be ready, returning its process ID.
+pub async fn upgrade(sockets: &Sockets) -> Result<u32> {
+    let mut fds = vec![];
+    let mut listeners = String::new();
+    for (addr, sockets) in sockets {
+        for socket in sockets {
+            let fd = socket
+                .raw_fd()
+                .ok_or_else(|| anyhow!("{addr} cannot be passed to another process"))?;
+            fds.push(fd);
+            listeners += &format!("{fd}={addr}\n");
+        }
     }
 
-    Ok(())
+    let (ready_rx, ready_tx) = std::io::pipe().context("failed to create pipe")?;
+    fds.push(ready_tx.as_raw_fd());
+
+    let args: Vec<_> = std::env::args_os().collect();
+    let (program, args) = args.split_first().context("no program name")?;
+    let mut command = upgrade_command(program, args);
+    command
+        .env(LISTENERS_ENV, listeners)
+        .env(READY_ENV, ready_tx.as_raw_fd().to_string());
+    // lets the new process inherit the sockets, all of which are opened as close-on-exec
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &fds {
+                if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn().context("failed to start new process")?;
+    let pid = child.id().context("new process exited")?;
+    drop(ready_tx);
+
+    wait_ready(child, ready_rx, READY_TIMEOUT).await?;
+    Ok(pid)
+}
+
+/// Creates the command starting the new process on upgrade.
+fn upgrade_command(program: &OsStr, args: &[OsString]) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(program);
+    // the watchdog of systemd is disabled for any process but the one `WATCHDOG_PID` names, which
+    // is this one, so the new process only pings it by `WATCHDOG_USEC` without the variable
+    command.args(args).env_remove("WATCHDOG_PID");
+    command
+}
+
+/// Waits for the new process `child` to report its readiness through `ready`, killing it if it
+/// is not ready within `limit`.
+async fn wait_ready(mut child: Child, ready: std::io::PipeReader, limit: Duration) -> Result<()> {
+    let mut ready =
+        pipe::Receiver::from_owned_fd(ready.into()).context("failed to wait for new process")?;
+    let mut buf = [0];
+    let res = match tokio::time::timeout(limit, ready.read(&mut buf)).await {
+        Ok(Ok(1)) => return Ok(()),
+        // the pipe is closed without a report if the new process exits
+        Ok(Ok(_)) => {
+            let status = child.wait().await?;
+            return Err(anyhow!("new process exited with {status}"));
+        }
+        Ok(Err(e)) => Err(e).context("failed to wait for new process"),
+        Err(_) => Err(anyhow!("new process is not ready in {limit:?}")),
+    };
+
+    child.kill().await.context("failed to kill new process")?;
+    res
+}
+
+/// Takes the sockets passed by the previous process on upgrade, keyed by their addresses.
+pub fn inherited_listeners() -> Result<Option<Sockets>> {
+    let Ok(listeners) = std::env::var(LISTENERS_ENV) else {
+        return Ok(None);
+    };
+
+    parse_listeners(&listeners).map(Some)
+}
+
+/// Takes the sockets in `listeners` given as the value of [`LISTENERS_ENV`].
+fn parse_listeners(listeners: &str) -> Result<Sockets> {
+    let entries = listeners
+        .lines()
+        .map(|line| {
+            line.split_once('=')
+                .and_then(|(fd, addr)| Some((fd.parse().ok()?, addr)))
+                .with_context(|| format!("invalid {LISTENERS_ENV}: `{line}`"))
+        })
+        .collect::<Result<Vec<(RawFd, _)>>>()?;
+    // owns all the sockets first, so that none is left open on failure
+    let entries: Vec<_> = entries
+        .into_iter()
+        .map(|(fd, addr)| (unsafe { OwnedFd::from_raw_fd(fd) }, addr))
+        .collect();
+
+    let mut sockets = Sockets::new();
+    for (fd, addr) in entries {
+        let listener = upgrade_listener(fd, addr)
+            .with_context(|| format!("failed to inherit socket of {addr}"))?;
+        sockets.entry(addr.to_string()).or_default().push(listener);
+    }
+
+    Ok(sockets)
+}
+
+/// Reports the readiness to the previous process on upgrade, if any.
+pub fn report_ready() -> Result<()> {
+    let Ok(fd) = std::env::var(READY_ENV) else {
+        return Ok(());
+    };
+
+    let fd = fd
+        .parse()
+        .with_context(|| format!("invalid {READY_ENV}: `{fd}`"))?;
+    let mut pipe = std::fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    std::io::Write::write_all(&mut pipe, b"1").context("failed to report readiness")
 }
 
 #[cfg(target_os = "macos")]
-pub fn activate_socket(name: &str) -> Result<Vec<TcpListener>> {
-    launchd::activate_socket(name)
+pub fn activate_socket(name: &str) -> Result<Vec<Arc<dyn Listener>>> {
+    let fds: Vec<_> = launchd::activate_socket(name)
         .context("failed to activate from launchd")?
         .into_iter()
-        .map(|fd| unsafe { FromRawFd::from_raw_fd(fd) })
-        .map(upgrade_listener)
-        .collect()
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect();
+    let mut listeners = vec![];
+    for fd in fds {
+        listeners.push(upgrade_listener(fd, name)?);
+    }
+    Ok(listeners)
 }
 
 #[cfg(target_os = "macos")]
 mod launchd {
     use super::*;
     use libc::{c_char, c_int, size_t};
-    use std::ffi::CString;
     use std::ptr::null_mut;
     use std::slice::from_raw_parts;
 
@@ -59,18 +223,409 @@ mod launchd {
 }
 
 #[cfg(all(target_os = "linux", feature = "systemd"))]
-pub fn activate_socket() -> Result<Vec<TcpListener>> {
-    systemd::daemon::listen_fds(true)
-        .context("failed to activate from systemd")?
+pub fn activate_socket() -> Result<Vec<Arc<dyn Listener>>> {
+    Ok(activate_named_sockets()?.into_values().flatten().collect())
+}
+
+/// Takes the sockets passed by systemd, keyed by their names in `LISTEN_FDNAMES`, which are set
+/// with `FileDescriptorName=`.
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+pub fn activate_named_sockets() -> Result<Sockets> {
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let fds = systemd::daemon::listen_fds(true).context("failed to activate from systemd")?;
+
+    // owns all the sockets first, so that none is left open on failure
+    let fds: Vec<_> = fds
         .iter()
-        .map(|fd| unsafe { FromRawFd::from_raw_fd(fd) })
-        .map(upgrade_listener)
-        .collect()
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect();
+
+    let mut names = names.split(':');
+    let mut sockets = Sockets::new();
+    for fd in fds {
+        // systemd names sockets `unknown` if it cannot tell
+        let name = names.next().filter(|n| !n.is_empty()).unwrap_or("unknown");
+        let listener = upgrade_listener(fd, name)
+            .with_context(|| format!("failed to activate socket `{name}`"))?;
+        sockets.entry(name.to_string()).or_default().push(listener);
+    }
+
+    Ok(sockets)
+}
+
+/// Notifies systemd, if it supervises this process, of the state given as the variables of
+/// `sd_notify(3)`.
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+pub fn notify(state: &[(&str, &str)]) -> Result<()> {
+    systemd::daemon::notify(false, state.iter()).context("failed to notify systemd")?;
+    Ok(())
 }
 
-fn upgrade_listener(listener: std::net::TcpListener) -> Result<TcpListener> {
+/// Returns the timeout of the watchdog of systemd, if enabled with `WatchdogSec=`.
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+pub fn watchdog_timeout() -> Result<Option<Duration>> {
+    let usec =
+        systemd::daemon::watchdog_enabled(false).context("failed to get watchdog timeout")?;
+    Ok((usec > 0).then(|| Duration::from_micros(usec)))
+}
+
+/// Converts an activated socket `name`, either TCP or Unix domain, into a listener.
+///
+/// Fails on other sockets, like datagram ones, as no provider serves them.
+fn upgrade_listener(fd: OwnedFd, name: &str) -> Result<Arc<dyn Listener>> {
+    if !is_stream_socket(fd.as_fd())? {
+        return Err(anyhow!("socket `{name}` is not a stream socket"));
+    }
+
+    if is_unix_socket(&fd)? {
+        let listener = std::os::unix::net::UnixListener::from(fd);
+        listener
+            .set_nonblocking(true)
+            .context("failed to set in non-blocking mode")?;
+        let listener = UnixListener::from_std(listener).context("failed to convert socket")?;
+        return Ok(Arc::new(listener));
+    }
+
+    let listener = std::net::TcpListener::from(fd);
     listener
         .set_nonblocking(true)
         .context("failed to set in non-blocking mode")?;
-    TcpListener::from_std(listener).context("failed to convert socket")
+    let listener = TcpListener::from_std(listener).context("failed to convert socket")?;
+    Ok(Arc::new(listener))
+}
+
+/// Takes the connection passed by systemd with `Accept=yes`, or otherwise by inetd as stdin, if
+/// any.
+pub fn inherited_connection() -> Result<Option<Connection>> {
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    if let Some(fd) = systemd::daemon::listen_fds(true)
+        .context("failed to activate from systemd")?
+        .iter()
+        .next()
+    {
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        return upgrade_stream(fd).map(Some);
+    }
+
+    if !is_socket(std::io::stdin().as_fd()) {
+        return Ok(None);
+    }
+    let fd = std::io::stdin()
+        .as_fd()
+        .try_clone_to_owned()
+        .context("failed to duplicate stdin")?;
+    upgrade_stream(fd).map(Some)
+}
+
+/// Returns whether stderr is a socket, which inetd passes the connection as.
+pub fn stderr_is_socket() -> bool {
+    is_socket(std::io::stderr().as_fd())
+}
+
+/// Converts an inherited connection, either TCP or Unix domain, into a connection to serve.
+fn upgrade_stream(fd: OwnedFd) -> Result<Connection> {
+    if !is_stream_socket(fd.as_fd())? {
+        return Err(anyhow!("only stream sockets are supported"));
+    }
+
+    if is_unix_socket(&fd)? {
+        let stream = std::os::unix::net::UnixStream::from(fd);
+        stream
+            .set_nonblocking(true)
+            .context("failed to set in non-blocking mode")?;
+        let stream = UnixStream::from_std(stream).context("failed to convert socket")?;
+        return Ok(Connection::from(stream).boxed());
+    }
+
+    let stream = std::net::TcpStream::from(fd);
+    stream
+        .set_nonblocking(true)
+        .context("failed to set in non-blocking mode")?;
+    let stream = TcpStream::from_std(stream).context("failed to convert socket")?;
+    Ok(Connection::from(stream).boxed())
+}
+
+fn is_socket(fd: BorrowedFd) -> bool {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) };
+    ret == 0 && stat.st_mode & libc::S_IFMT == libc::S_IFSOCK
+}
+
+fn is_stream_socket(fd: BorrowedFd) -> Result<bool> {
+    let mut ty: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&ty) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut ty as *mut _ as *mut _,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error()).context("failed to get socket type");
+    }
+
+    Ok(ty == libc::SOCK_STREAM)
+}
+
+fn is_unix_socket(fd: &OwnedFd) -> Result<bool> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of_val(&addr) as libc::socklen_t;
+    if unsafe { libc::getsockname(fd.as_raw_fd(), &mut addr as *mut _ as *mut _, &mut len) } == -1 {
+        return Err(io::Error::last_os_error()).context("failed to get socket address");
+    }
+
+    Ok(i32::from(addr.ss_family) == libc::AF_UNIX)
+}
+
+/// Binds a Unix domain socket to `path`, or the abstract `name` if `path` is `@<name>`.
+///
+/// A socket file left by a process no longer listening on it is replaced.
+pub fn bind_unix(path: &str, config: &SocketConfig) -> Result<Arc<dyn Listener>> {
+    if let Some(name) = path.strip_prefix('@') {
+        if !cfg!(any(target_os = "linux", target_os = "android")) {
+            return Err(anyhow!("abstract sockets are not supported: unix:{path}"));
+        }
+        check_abstract(path, config)?;
+        let listener = UnixListener::bind(format!("\0{name}"))
+            .with_context(|| format!("failed to bind to unix:{path}"))?;
+        return Ok(Arc::new(listener));
+    }
+
+    let path = Path::new(path);
+    if path.metadata().is_ok_and(|m| m.file_type().is_socket())
+        && std::os::unix::net::UnixStream::connect(path)
+            .is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused)
+    {
+        std::fs::remove_file(path)
+            .with_context(|| format!("failed to remove stale socket {}", path.display()))?;
+    }
+
+    let socket = tokio::net::UnixSocket::new_stream().context("failed to create socket")?;
+    socket
+        .bind(path)
+        .with_context(|| format!("failed to bind to unix:{}", path.display()))?;
+
+    // no one can connect until listening, so the owner and permissions are set before that
+    let listener = set_owner_and_mode(path, config, config.mode).and_then(|()| {
+        socket
+            .listen(1024)
+            .with_context(|| format!("failed to listen on unix:{}", path.display()))
+    });
+    match listener {
+        Ok(listener) => Ok(Arc::new(listener)),
+        Err(e) => {
+            let _ = std::fs::remove_file(path);
+            Err(e)
+        }
+    }
+}
+
+/// Applies the owner and permissions of `config` to the socket file at `path`, bound on an earlier
+/// load, keeping its permissions if none are configured.
+pub fn update_unix(path: &str, config: &SocketConfig) -> Result<()> {
+    if path.starts_with('@') {
+        return check_abstract(path, config);
+    }
+
+    set_owner_and_mode(Path::new(path), config, config.mode)
+}
+
+/// Fails if `config` sets the owner or permissions of the abstract socket `path`, which has no
+/// file to set them on.
+fn check_abstract(path: &str, config: &SocketConfig) -> Result<()> {
+    if config.mode.is_some() || config.owner.is_some() || config.group.is_some() {
+        return Err(anyhow!(
+            "abstract sockets have no permissions or owner to set: unix:{path}"
+        ));
+    }
+    Ok(())
+}
+
+/// Sets the owner of the socket file at `path`, if configured, and then its permissions, if any,
+/// so that no one but the intended owner is let in.
+fn set_owner_and_mode(path: &Path, config: &SocketConfig, mode: Option<u32>) -> Result<()> {
+    if config.owner.is_some() || config.group.is_some() {
+        let uid = config.owner.as_deref().map(user_id).transpose()?;
+        let gid = config.group.as_deref().map(group_id).transpose()?;
+        std::os::unix::fs::chown(path, uid, gid)
+            .with_context(|| format!("failed to change owner of {}", path.display()))?;
+    }
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to set permissions of {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Looks up the ID of the user named `name`, which may be the ID itself.
+fn user_id(name: &str) -> Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    let name = CString::new(name)?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(anyhow!("unknown user: `{}`", name.to_string_lossy()));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+/// Looks up the ID of the group named `name`, which may be the ID itself.
+fn group_id(name: &str) -> Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    let name = CString::new(name)?;
+    let group = unsafe { libc::getgrnam(name.as_ptr()) };
+    if group.is_null() {
+        return Err(anyhow!("unknown group: `{}`", name.to_string_lossy()));
+    }
+    Ok(unsafe { (*group).gr_gid })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let path = std::env::temp_dir().join(format!("juno-bind-{}.sock", std::process::id()));
+        let addr = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let config = SocketConfig {
+            mode: Some(0o640),
+            ..Default::default()
+        };
+        let listener = bind_unix(addr, &config).unwrap();
+        let mode = path.metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        drop(listener);
+
+        // replaces the stale socket, but leaves nothing when the owner is unknown
+        let config = SocketConfig {
+            owner: Some("juno-no-such-user".to_string()),
+            ..Default::default()
+        };
+        assert!(bind_unix(addr, &config).is_err());
+        assert!(!path.exists());
+
+        // applies changes on reload, keeping permissions not configured
+        let _listener = bind_unix(addr, &SocketConfig::default()).unwrap();
+        let mode = |path: &Path| path.metadata().unwrap().permissions().mode() & 0o777;
+        let config = SocketConfig {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+        update_unix(addr, &config).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        update_unix(addr, &SocketConfig::default()).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        let _ = std::fs::remove_file(&path);
+
+        // abstract sockets have no file to set permissions on
+        #[cfg(target_os = "linux")]
+        {
+            let name = format!("@juno-bind-{}", std::process::id());
+            let config = SocketConfig {
+                mode: Some(0o600),
+                ..Default::default()
+            };
+            assert!(bind_unix(&name, &config).is_err());
+            let _listener = bind_unix(&name, &SocketConfig::default()).unwrap();
+            assert!(update_unix(&name, &config).is_err());
+            assert!(update_unix(&name, &SocketConfig::default()).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parse_listeners() {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let path = std::env::temp_dir().join(format!("juno-upgrade-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+
+        // passes duplicates as the parsed listeners take the ownership
+        let dup = |l: &dyn Listener| unsafe { libc::dup(l.raw_fd().unwrap()) };
+        let (tcp_fd, unix_fd) = (dup(&tcp), dup(&unix));
+        let listeners = format!("{tcp_fd}={addr}\n{unix_fd}=unix:{}\n", path.display());
+        let sockets = parse_listeners(&listeners).unwrap();
+        assert_eq!(sockets.len(), 2);
+
+        let inherited = &sockets[&addr.to_string()][0];
+        assert_eq!(inherited.local_addr().unwrap(), addr.to_string());
+        let _client = TcpStream::connect(addr).await.unwrap();
+        assert!(inherited.accept().await.is_ok());
+
+        let inherited = &sockets[&format!("unix:{}", path.display())][0];
+        let _client = UnixStream::connect(&path).await.unwrap();
+        assert!(inherited.accept().await.is_ok());
+        let _ = std::fs::remove_file(&path);
+
+        assert!(parse_listeners("").unwrap().is_empty());
+        assert!(parse_listeners("3").is_err());
+        assert!(parse_listeners("x=127.0.0.1:80").is_err());
+
+        // fails on datagram sockets, naming them
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let datagram = std::os::unix::net::UnixDatagram::unbound().unwrap();
+        let listeners = format!(
+            "{}=unix:datagram\n{}={addr}\n",
+            datagram.into_raw_fd(),
+            dup(&tcp)
+        );
+        let Err(err) = parse_listeners(&listeners) else {
+            panic!("datagram socket inherited");
+        };
+        assert!(format!("{err:#}").contains("socket `unix:datagram` is not a stream socket"));
+    }
+
+    #[test]
+    fn test_upgrade_command() {
+        // the new process pings the watchdog enabled for this one
+        let command = upgrade_command(OsStr::new("juno"), &["--systemd".into()]);
+        let command = command.as_std();
+        assert_eq!(command.get_args().collect::<Vec<_>>(), ["--systemd"]);
+        assert!(command
+            .get_envs()
+            .any(|(key, value)| key == "WATCHDOG_PID" && value.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_wait_ready() {
+        let sleep = || {
+            tokio::process::Command::new("sleep")
+                .arg("10")
+                .kill_on_drop(true)
+                .spawn()
+                .unwrap()
+        };
+        let limit = Duration::from_secs(10);
+
+        let (rx, mut tx) = std::io::pipe().unwrap();
+        std::io::Write::write_all(&mut tx, b"1").unwrap();
+        assert!(wait_ready(sleep(), rx, limit).await.is_ok());
+
+        let (rx, tx) = std::io::pipe().unwrap();
+        drop(tx);
+        let child = tokio::process::Command::new("true").spawn().unwrap();
+        let err = wait_ready(child, rx, limit).await.unwrap_err();
+        assert!(err.to_string().contains("exited"));
+
+        // kills and reaps the process not ready in time
+        let (rx, _tx) = std::io::pipe().unwrap();
+        let child = sleep();
+        let pid = child.id().unwrap() as libc::pid_t;
+        assert!(wait_ready(child, rx, Duration::from_millis(100))
+            .await
+            .is_err());
+        assert_eq!(unsafe { libc::kill(pid, 0) }, -1);
+    }
 }
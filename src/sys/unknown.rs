@@ -1 +1,55 @@
-pub use tokio::signal::ctrl_c as recv_signal;
+use futures::prelude::*;
+use tokio::io;
+
+/// Returns a stream yielding on each request to shut down, which is Ctrl-C.
+pub fn shutdown_signal() -> io::Result<impl Stream<Item = ()>> {
+    Ok(stream::unfold((), |_| async {
+        tokio::signal::ctrl_c().await.ok().map(|_| ((), ()))
+    }))
+}
+
+/// Returns a stream yielding on each request to reload the configuration, which never happens.
+pub fn reload_signal() -> io::Result<impl Stream<Item = ()>> {
+    Ok(stream::pending())
+}
+
+pub fn bind_unix(
+    path: &str,
+    _: &juno::config::SocketConfig,
+) -> anyhow::Result<std::sync::Arc<dyn juno::server::Listener>> {
+    Err(anyhow::anyhow!(
+        "Unix domain sockets are not supported: unix:{path}"
+    ))
+}
+
+/// Updates a Unix domain socket, which is never bound.
+pub fn update_unix(_: &str, _: &juno::config::SocketConfig) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Takes the connection passed by inetd, which never happens.
+pub fn inherited_connection() -> anyhow::Result<Option<juno::Connection>> {
+    Ok(None)
+}
+
+pub fn stderr_is_socket() -> bool {
+    false
+}
+
+/// Returns a stream yielding on each request to upgrade to a new binary, which never happens.
+pub fn upgrade_signal() -> io::Result<impl Stream<Item = ()>> {
+    Ok(stream::pending())
+}
+
+pub async fn upgrade(_: &crate::Sockets) -> anyhow::Result<u32> {
+    Err(anyhow::anyhow!("upgrading is not supported"))
+}
+
+/// Takes the sockets passed by the previous process on upgrade, which never happens.
+pub fn inherited_listeners() -> anyhow::Result<Option<crate::Sockets>> {
+    Ok(None)
+}
+
+pub fn report_ready() -> anyhow::Result<()> {
+    Ok(())
+}
@@ -1,6 +1,9 @@
+use futures::prelude::*;
+use std::task::Poll;
 use tokio::io;
 
-pub async fn recv_signal() -> io::Result<()> {
+/// Returns a stream yielding on each request to shut down.
+pub fn shutdown_signal() -> io::Result<impl Stream<Item = ()>> {
     use tokio::signal::windows::*;
 
     let mut ctrl_c = ctrl_c()?;
@@ -8,14 +11,65 @@ pub async fn recv_signal() -> io::Result<()> {
     let mut ctrl_close = ctrl_close()?;
     let mut ctrl_logoff = ctrl_logoff()?;
     let mut ctrl_shutdown = ctrl_shutdown()?;
+    Ok(stream::poll_fn(move |cx| {
+        // polls all not to miss waking up on any
+        let polls = [
+            ctrl_c.poll_recv(cx),
+            ctrl_break.poll_recv(cx),
+            ctrl_close.poll_recv(cx),
+            ctrl_logoff.poll_recv(cx),
+            ctrl_shutdown.poll_recv(cx),
+        ];
+        if polls.iter().all(Poll::is_pending) {
+            Poll::Pending
+        } else {
+            Poll::Ready(Some(()))
+        }
+    }))
+}
+
+/// Returns a stream yielding on each request to reload the configuration, which never happens.
+pub fn reload_signal() -> io::Result<impl Stream<Item = ()>> {
+    Ok(stream::pending())
+}
+
+pub fn bind_unix(
+    path: &str,
+    _: &juno::config::SocketConfig,
+) -> anyhow::Result<std::sync::Arc<dyn juno::server::Listener>> {
+    Err(anyhow::anyhow!(
+        "Unix domain sockets are not supported: unix:{path}"
+    ))
+}
 
-    tokio::select! {
-        _ = ctrl_c.recv() => {},
-        _ = ctrl_break.recv() => {},
-        _ = ctrl_close.recv() => {},
-        _ = ctrl_logoff.recv() => {},
-        _ = ctrl_shutdown.recv() => {},
-    }
+/// Updates a Unix domain socket, which is never bound.
+pub fn update_unix(_: &str, _: &juno::config::SocketConfig) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Takes the connection passed by inetd, which never happens.
+pub fn inherited_connection() -> anyhow::Result<Option<juno::Connection>> {
+    Ok(None)
+}
+
+pub fn stderr_is_socket() -> bool {
+    false
+}
+
+/// Returns a stream yielding on each request to upgrade to a new binary, which never happens.
+pub fn upgrade_signal() -> io::Result<impl Stream<Item = ()>> {
+    Ok(stream::pending())
+}
+
+pub async fn upgrade(_: &crate::Sockets) -> anyhow::Result<u32> {
+    Err(anyhow::anyhow!("upgrading is not supported"))
+}
+
+/// Takes the sockets passed by the previous process on upgrade, which never happens.
+pub fn inherited_listeners() -> anyhow::Result<Option<crate::Sockets>> {
+    Ok(None)
+}
 
+pub fn report_ready() -> anyhow::Result<()> {
     Ok(())
 }
@@ -0,0 +1,246 @@
+use crate::client::{self, Reply};
+use crate::connector::{BoxIo, Connector};
+use crate::route::Context;
+use crate::socks::v5;
+use crate::{Address, DialError};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io;
+
+/// Proxy server outbound connections are relayed through.
+///
+/// Written as `http://<host>:<port>`, `socks4a://<host>:<port>` or `socks5://<host>:<port>`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Upstream {
+    Http(Address),
+    Socks4(Address),
+    Socks5(Address),
+}
+
+impl Upstream {
+    /// Returns the address of the proxy server.
+    pub fn addr(&self) -> &Address {
+        match self {
+            Self::Http(addr) | Self::Socks4(addr) | Self::Socks5(addr) => addr,
+        }
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| format!("scheme is missing in `{s}`"))?;
+
+        let addr = rest.trim_end_matches('/').parse()?;
+
+        match scheme {
+            "http" => Ok(Self::Http(addr)),
+            "socks4" | "socks4a" => Ok(Self::Socks4(addr)),
+            "socks5" => Ok(Self::Socks5(addr)),
+            _ => Err(format!("unsupported scheme `{scheme}`")),
+        }
+    }
+}
+
+impl TryFrom<String> for Upstream {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Time handshakes with upstream proxies are limited to by default.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connects through an upstream proxy, which is reached by another connector.
+pub struct Proxy {
+    upstream: Upstream,
+    connector: Arc<dyn Connector>,
+    timeout: Duration,
+}
+
+impl Proxy {
+    pub fn new(upstream: Upstream, connector: Arc<dyn Connector>) -> Self {
+        Self {
+            upstream,
+            connector,
+            timeout: HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Limits the time spent on the handshake with the proxy, once connected to it. Defaults to
+    /// 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Connector for Proxy {
+    fn connect<'a>(
+        &'a self,
+        ctx: &'a Context,
+        addr: &'a Address,
+    ) -> BoxFuture<'a, Result<BoxIo, DialError>> {
+        async move {
+            let mut stream = self.connector.connect(ctx, self.upstream.addr()).await?;
+            let handshake = client::connect(&mut stream, &self.upstream, addr, None);
+            let reply = tokio::time::timeout(self.timeout, handshake)
+                .await
+                .map_err(|_| DialError::TimedOut)??;
+            if !reply.is_success() {
+                return Err(reply_error(reply));
+            }
+            Ok(stream)
+        }
+        .boxed()
+    }
+}
+
+/// Maps the failure `reply` of an upstream proxy to the error clients are told of, as if the
+/// listener failed to connect itself.
+fn reply_error(reply: Reply) -> DialError {
+    match reply {
+        Reply::Http(403) | Reply::Socks5(v5::Reply::NotAllowed) => DialError::Rejected,
+        Reply::Http(504) | Reply::Socks5(v5::Reply::TtlExpired) => DialError::TimedOut,
+        _ => io::Error::other(format!("upstream responded with {reply}")).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connector::Duplex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(
+            "http://proxy.example:3128".parse(),
+            Ok(Upstream::Http(Address::new("proxy.example", 3128)))
+        );
+        assert_eq!(
+            "socks5://[2001:db8::1]:1080".parse(),
+            Ok(Upstream::Socks5(Address::new("[2001:db8::1]", 1080)))
+        );
+        assert_eq!(
+            "socks4a://proxy.example:1080".parse(),
+            Ok(Upstream::Socks4(Address::new("proxy.example", 1080)))
+        );
+        assert!("proxy.example:3128".parse::<Upstream>().is_err());
+        assert!("http://proxy.example".parse::<Upstream>().is_err());
+        assert!("ftp://proxy.example:21".parse::<Upstream>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let (connector, mut server) = Duplex::new();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let len = server.read(&mut buf).await.unwrap();
+            assert!(buf[..len].starts_with(b"CONNECT example.com:443 HTTP/1.1\r\n"));
+            server
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello")
+                .await
+                .unwrap();
+        });
+
+        let proxy = Proxy::new(
+            Upstream::Http(Address::new("proxy.example", 3128)),
+            Arc::new(connector),
+        );
+        let mut stream = proxy
+            .connect(&Context::default(), &Address::new("example.com", 443))
+            .await
+            .unwrap();
+
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "hello");
+    }
+
+    /// Connects through a SOCKS5 proxy replying with `reply`.
+    async fn socks5_connect(reply: u8) -> Result<BoxIo, DialError> {
+        let (connector, mut server) = Duplex::new();
+        tokio::spawn(async move {
+            let mut buf = [0; 3];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 1, 0]);
+            server.write_all(&[5, 0]).await.unwrap();
+
+            let mut buf = [0; 10];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 1, 0, 1, 192, 0, 2, 1, 0, 80]);
+            server
+                .write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        let proxy = Proxy::new(
+            Upstream::Socks5(Address::new("proxy.example", 1080)),
+            Arc::new(connector),
+        );
+        proxy
+            .connect(&Context::default(), &Address::new("192.0.2.1", 80))
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect() {
+        assert!(socks5_connect(0).await.is_ok());
+        assert!(matches!(socks5_connect(2).await, Err(DialError::Rejected)));
+        assert!(matches!(socks5_connect(6).await, Err(DialError::TimedOut)));
+        assert!(matches!(socks5_connect(4).await, Err(DialError::Io(_))));
+    }
+
+    /// Connects through an HTTP proxy responding with `status`.
+    async fn http_connect(status: u16) -> Result<BoxIo, DialError> {
+        let (connector, mut server) = Duplex::new();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let _ = server.read(&mut buf).await.unwrap();
+            let res = format!("HTTP/1.1 {status} Status\r\n\r\n");
+            server.write_all(res.as_bytes()).await.unwrap();
+        });
+
+        let proxy = Proxy::new(
+            Upstream::Http(Address::new("proxy.example", 3128)),
+            Arc::new(connector),
+        );
+        proxy
+            .connect(&Context::default(), &Address::new("example.com", 443))
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_failure() {
+        assert!(matches!(http_connect(403).await, Err(DialError::Rejected)));
+        assert!(matches!(http_connect(504).await, Err(DialError::TimedOut)));
+        assert!(matches!(http_connect(502).await, Err(DialError::Io(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        // the proxy never responds
+        let (connector, _server) = Duplex::new();
+        let proxy = Proxy::new(
+            Upstream::Http(Address::new("proxy.example", 3128)),
+            Arc::new(connector),
+        )
+        .timeout(Duration::from_secs(5));
+        let res = proxy
+            .connect(&Context::default(), &Address::new("example.com", 443))
+            .await;
+        assert!(matches!(res, Err(DialError::TimedOut)));
+    }
+}